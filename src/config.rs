@@ -0,0 +1,1556 @@
+// Minimizer -- Site minifier for MkDocs sites that use the Kilsbergen theme
+// Copyright 2022 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Command-line options, and the config file that can set them.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use minimizer::{
+    BrotliMode, SanityCheck, Sizes, TrailingNewline, BROTLI_LEVEL_DEFAULT, DEFAULT_BANNER,
+    ZOPFLI_ITERATIONS_DEFAULT,
+};
+
+/// Extensions of files that Brotli compresses as text, besides the pages.
+const BROTLI_TEXT_EXTENSIONS: [&str; 5] = ["css", "js", "mjs", "svg", "map"];
+
+/// The config file that we load if no `--config` is given, if it exists.
+const CONFIG_PATH_DEFAULT: &str = "minimizer.toml";
+
+/// The directory in the source branch with its config, for `--config-from-repo`.
+pub const REPO_CONFIG_DIR: &str = ".minimizer";
+
+/// The config and banner that the source branch has in [`REPO_CONFIG_DIR`].
+#[derive(Default)]
+pub struct RepoConfig {
+    /// Content of `config.toml`, which replaces the local config file.
+    pub config: Option<String>,
+
+    /// Content of `banner.html`, which replaces the banner of the config.
+    pub banner: Option<String>,
+}
+
+/// Which of the Gzip and Brotli versions of a page to put in the tree.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Keep {
+    /// Both the Gzip and the Brotli version.
+    Both,
+    /// Only the Gzip version.
+    Gzip,
+    /// Only the Brotli version.
+    Brotli,
+    /// Whichever of the two is smaller, for every page individually.
+    Smallest,
+}
+
+/// What to do when the source branch moves while we minimize it.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum SourceMoved {
+    /// Print a warning, but check out the result anyway.
+    Warn,
+    /// Exit with an error, without checking out.
+    Fail,
+    /// Minimize the new source tree.
+    Restart,
+}
+
+/// Where pages get a second copy, for hosts with a different URL convention.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum PageAlias {
+    /// Copy `foo.html` to `foo/index.html`.
+    Dir,
+    /// Copy `foo/index.html` to `foo.html`.
+    Flat,
+}
+
+/// Parse the value of `--alias-pages`.
+fn parse_page_alias(value: &str) -> PageAlias {
+    match value {
+        "dir" => PageAlias::Dir,
+        "flat" => PageAlias::Flat,
+        _ => panic!("Expected dir or flat after --alias-pages, got {}.", value),
+    }
+}
+
+/// How to order the entries for the `cache-list` subcommand.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum CacheOrder {
+    /// By the oid of the source blob, as in the cache file.
+    Blob,
+    /// Largest Brotli version first.
+    Size,
+    /// Smallest savings of the Brotli version first.
+    Savings,
+}
+
+/// Parse the value of `--sort`.
+fn parse_cache_order(value: &str) -> CacheOrder {
+    match value {
+        "blob" => CacheOrder::Blob,
+        "size" => CacheOrder::Size,
+        "savings" => CacheOrder::Savings,
+        _ => panic!("Expected blob, size, or savings after --sort, got {}.", value),
+    }
+}
+
+/// How much to minify html pages.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Preset {
+    /// Minify as much as we can.
+    Production,
+    /// Collapse whitespace, but keep comments, CSS, and scripts readable.
+    Staging,
+}
+
+/// Parse the value of `--preset`.
+fn parse_preset(value: &str) -> Preset {
+    match value {
+        "production" => Preset::Production,
+        "staging" => Preset::Staging,
+        _ => panic!("Expected production or staging after --preset, got {}.", value),
+    }
+}
+
+/// Parse the value of `--on-source-moved`.
+fn parse_source_moved(value: &str) -> SourceMoved {
+    match value {
+        "warn" => SourceMoved::Warn,
+        "fail" => SourceMoved::Fail,
+        "restart" => SourceMoved::Restart,
+        _ => panic!("Expected warn, fail, or restart after --on-source-moved, got {}.", value),
+    }
+}
+
+/// Parse a mode for `--brotli-mode`.
+fn parse_brotli_mode(name: &str) -> BrotliMode {
+    BrotliMode::from_name(name).unwrap_or_else(|| {
+        panic!("Expected generic, text, or font after --brotli-mode, got {}.", name)
+    })
+}
+
+/// Parse the value of `--prefer`.
+fn parse_prefer(value: &str) -> Keep {
+    match value {
+        "gz" => Keep::Gzip,
+        "br" => Keep::Brotli,
+        _ => panic!("Expected gz or br after --prefer, got {}.", value),
+    }
+}
+
+/// Parse the value of `--normalize-newlines`.
+fn parse_trailing_newline(value: &str) -> TrailingNewline {
+    match value {
+        "keep" => TrailingNewline::Keep,
+        "ensure" => TrailingNewline::Ensure,
+        "strip" => TrailingNewline::Strip,
+        _ => panic!("Expected keep, ensure, or strip after --normalize-newlines, got {}.", value),
+    }
+}
+
+/// Parse the value of `--layout`, return the Gzip, Brotli, and Deflate templates.
+fn parse_layout(value: &str) -> (Option<String>, Option<String>, Option<String>) {
+    let templates = |dir: &str| {
+        (
+            Some(format!("{dir}{{path}}.gz")),
+            Some(format!("{dir}{{path}}.br")),
+            Some(format!("{dir}{{path}}.deflate")),
+        )
+    };
+    match value {
+        // Nginx with `gzip_static` and `brotli_static` looks for siblings.
+        "nginx-static" => templates(""),
+        // A parallel tree, for servers that map encodings to a separate root.
+        "sidecar" => templates("_compressed/"),
+        // Cloudflare compresses at the edge, it does not use compressed files.
+        "cloudflare" => (None, None, None),
+        _ => panic!("Expected nginx-static, sidecar, or cloudflare after --layout, got {}.", value),
+    }
+}
+
+/// Parse the name of a check for `--sanity-check`.
+fn parse_sanity_check(name: &str) -> SanityCheck {
+    SanityCheck::from_name(name)
+        .unwrap_or_else(|| panic!("Expected tags or ids after --sanity-check, got {}.", name))
+}
+
+/// Read the license text for the banner from a file, for `--banner-file`.
+fn read_banner(fname: &str) -> String {
+    fs::read_to_string(fname)
+        .unwrap_or_else(|err| panic!("Failed to read banner {}: {}", fname, err))
+}
+
+/// Parse a layout template, an empty template means to not emit the file.
+fn parse_template(value: String) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+/// The branch to minimize if we get no `--branch`.
+pub const DEFAULT_BRANCH: &str = "gh-pages";
+
+/// How to call the minimizer, printed for `--help` and when arguments are missing.
+const USAGE: &str = "\
+Usage:
+  minimizer [options] <input-repo> [<output-directory>]
+  minimizer [options] checkout <input-repo> <tree> <output-directory>
+  minimizer [--force] init
+  minimizer [--cache <file>] [--sort <order>] cache-list
+  minimizer [options] [--write] file <file>
+  minimizer --report-schema
+
+Minimizes the gh-pages branch of <input-repo>, and checks out the result into
+<output-directory>. See the readme for the options.";
+
+/// Print the usage, and exit with a failure.
+fn exit_with_usage(problem: &str) -> ! {
+    println!("{}\n\n{}", problem, USAGE);
+    std::process::exit(1);
+}
+
+/// Options that control the minimizer.
+pub struct Options {
+    /// Path to the repository that contains the source branch.
+    pub repo_path: String,
+
+    /// Directory to check out the minimized tree into, if any.
+    pub target_path: Option<String>,
+
+    /// For the `checkout` subcommand, the tree to check out instead of minimizing.
+    pub checkout: Option<String>,
+
+    /// For the `init` subcommand, write a config file instead of minimizing.
+    pub init: bool,
+
+    /// For the `init` subcommand, overwrite the config file if it exists.
+    pub force: bool,
+
+    /// For the `cache-list` subcommand, print the cache instead of minimizing.
+    pub cache_list: bool,
+
+    /// For the `cache-list` subcommand, the order to print the entries in.
+    pub cache_order: CacheOrder,
+
+    /// For the `file` subcommand, the file to minify instead of a repository.
+    pub file: Option<String>,
+
+    /// For the `file` subcommand, write the results next to the file.
+    pub write: bool,
+
+    /// Read the config and banner from the source branch, if it has them.
+    pub config_from_repo: bool,
+
+    /// Branches to minimize, each into its own directory if there are several.
+    pub branches: Vec<String>,
+
+    /// Whether the branches were given, rather than the default.
+    ///
+    /// Only without them do we fall back to HEAD when the default does not
+    /// exist, an explicit `--branch gh-pages` must exist.
+    pub branches_given: bool,
+
+    /// If set, minimize the tree at this revision, rather than the branches.
+    pub rev: Option<String>,
+
+    /// If set, check after minimizing whether the source moved, and if so, act.
+    pub on_source_moved: Option<SourceMoved>,
+
+    /// Path of the tsv file that caches minified blobs across runs.
+    pub cache_path: String,
+
+    /// Drop cache entries that no run used in this many days.
+    pub max_cache_age_days: Option<u64>,
+
+    /// Save the cache by appending the new entries, rather than rewriting it.
+    pub cache_append: bool,
+
+    /// Extensions, without dot, of files to minify and compress.
+    pub html_extensions: Vec<String>,
+
+    /// Extensions, without dot, of XHTML files to minify gently and compress.
+    pub xhtml_extensions: Vec<String>,
+
+    /// Extensions, without dot, of XML files, to remove whitespace from.
+    pub xml_extensions: Vec<String>,
+
+    /// Extensions, without dot, of files to compress but not minify.
+    pub compress_extensions: Vec<String>,
+
+    /// Extensions, without dot, of files to copy into the tree unmodified.
+    pub passthrough_extensions: Vec<String>,
+
+    /// Directories in the root of the tree to drop entirely.
+    pub skip_dirs: Vec<String>,
+
+    /// Number of Zopfli iterations, if not scaled by input size.
+    pub zopfli_iterations: u8,
+
+    /// Scale the number of Zopfli iterations with the size of the input.
+    pub zopfli_adaptive: bool,
+
+    /// Fall back to fast compression for pages that take longer than this.
+    pub blob_timeout_secs: Option<u64>,
+
+    /// Brotli compression level, from 0 to 11.
+    pub brotli_level: u32,
+
+    /// Brotli compression levels by extension, that take precedence over
+    /// `brotli_level`.
+    pub brotli_levels: BTreeMap<String, u32>,
+
+    /// Brotli modes by extension, that take precedence over the mode we pick
+    /// from the kind of file.
+    pub brotli_modes: BTreeMap<String, BrotliMode>,
+
+    /// License text to put in a comment at the start of every html file.
+    pub banner: String,
+
+    /// If set, write a manifest of source to minified blobs to this path.
+    pub manifest_path: Option<String>,
+
+    /// Exit with an error if minification makes the site larger.
+    pub fail_on_growth: bool,
+
+    /// Warn about pages that are larger than this after Brotli compression.
+    pub budget_bytes: Option<usize>,
+
+    /// Exit with an error if any page exceeds the budget.
+    pub fail_on_budget: bool,
+
+    /// If set, write decompressed versions of the gz and br files here.
+    pub dump_decompressed_dir: Option<String>,
+
+    /// If set, inline linked stylesheets and scripts up to this size.
+    pub inline_max_bytes: Option<usize>,
+
+    /// After checkout, replace files with identical content by hardlinks.
+    pub hardlink_duplicates: bool,
+
+    /// Write the blobs to the target directory ourselves, rather than through
+    /// Git's checkout.
+    pub write_files: bool,
+
+    /// Also produce a raw Deflate-compressed version of every html file.
+    pub deflate: bool,
+
+    /// Copy files that match no extension list, rather than dropping them.
+    pub no_drop: bool,
+
+    /// Drop files that match no extension list, rather than refusing to.
+    pub drop_unrecognized: bool,
+
+    /// Leave out `.map` source maps, rather than compressing them.
+    pub drop_source_maps: bool,
+
+    /// Paths that must be in the minimized tree, or the run fails.
+    pub require: Vec<String>,
+
+    /// Look at the content of files, not just their name, to decide what they are.
+    pub sniff_content: bool,
+
+    /// Leave out the minified page where a compressed version is in the tree.
+    pub no_plain: bool,
+
+    /// Also put every page where the other URL convention expects it.
+    pub alias_pages: Option<PageAlias>,
+
+    /// The number of threads to walk subtrees on.
+    pub threads_walk: usize,
+
+    /// The number of pages that these threads may minify and compress at once.
+    pub threads_compress: usize,
+
+    /// Minimize without the cache on one thread, and check the cache agrees.
+    pub reproducible: bool,
+
+    /// Compress every page twice, and check that both results are identical.
+    pub check_determinism: bool,
+
+    /// If set, write a shields.io badge with the savings to this path.
+    pub badge_path: Option<String>,
+
+    /// Fail when minifying a page panics, instead of using the original page.
+    pub strict: bool,
+
+    /// If set, drop files that we would copy as-is if they are larger than this.
+    pub max_asset_bytes: Option<usize>,
+
+    /// Print a histogram of how well pages compress at the end of the run.
+    pub histogram: bool,
+
+    /// Time the decompression of the gz and br versions of every page.
+    pub measure_decode: bool,
+
+    /// Also report the size of the files that we copy as-is, and of the tree.
+    pub count_assets: bool,
+
+    /// External minifier commands, by extension without dot.
+    pub minifiers: BTreeMap<String, String>,
+
+    /// Extensions to treat files as, by path pattern, for files that have no
+    /// extension or the wrong one.
+    pub content_types: BTreeMap<String, String>,
+
+    /// Which compressed versions of pages to keep.
+    pub keep: Keep,
+
+    /// Drop cache entries whose blobs are missing from the repository.
+    pub verify_cache: bool,
+
+    /// Read every blob back after writing it, and compare it with what we wrote.
+    pub verify_writes: bool,
+
+    /// Minify cached pages again when the options for their kind changed.
+    pub only_changed_extensions: bool,
+
+    /// Where to put the Gzip version of a page, `None` to not emit it.
+    pub layout_gz: Option<String>,
+
+    /// Where to put the Brotli version of a page, `None` to not emit it.
+    pub layout_br: Option<String>,
+
+    /// Where to put the Deflate version of a page with `--deflate`, `None` to
+    /// not emit it.
+    pub layout_deflate: Option<String>,
+
+    /// Print the files that changed since the last checked out tree.
+    pub diff_tree: bool,
+
+    /// Print every entry of the minimized tree.
+    pub print_tree: bool,
+
+    /// Only keep compressed versions that are at least this much smaller.
+    pub min_savings_pct: Option<u32>,
+
+    /// If set, write Prometheus metrics about the run to this path.
+    pub metrics_path: Option<String>,
+
+    /// If set, write a JSON object per page to this path as we process them.
+    pub log_jsonl: Option<String>,
+
+    /// Check out even into a directory that may contain files we did not write.
+    pub force_overwrite: bool,
+
+    /// Commit the minimized tree on top of the source branch, and advance it.
+    pub commit_to_branch: bool,
+
+    /// Upload the minimized tree to this S3 URL, with the `s3` feature.
+    pub output_s3: Option<String>,
+
+    /// Also minify inline JavaScript in html pages, and report what it saves.
+    pub minify_js: bool,
+
+    /// How much to minify html pages.
+    pub preset: Preset,
+
+    /// Names of `<meta>` elements to remove from html pages.
+    pub strip_meta: Vec<String>,
+
+    /// Patterns of paths of pages to compress without minifying them.
+    pub no_minify: Vec<String>,
+
+    /// If set, convert line endings in minified markup to LF, and handle the
+    /// newline at the end of the document like this.
+    pub normalize_newlines: Option<TrailingNewline>,
+
+    /// If set, minimize only this many pages, and leave out the others.
+    pub limit: Option<usize>,
+
+    /// Minimize and report, but don't check out the result.
+    pub dry_run: bool,
+
+    /// Print the files and directories that we leave out of the tree, and why.
+    pub list_dropped: bool,
+
+    /// Print the pages that were not in the cache.
+    pub list_misses: bool,
+
+    /// If set, minimize this directory of the branch, rather than its root.
+    pub subdir: Option<String>,
+
+    /// Only report sizes, don't write objects, the cache, or the output.
+    pub stats_only: bool,
+
+    /// Checks to compare html pages before and after minification. When one
+    /// fails, we keep the original page.
+    pub sanity_checks: Vec<SanityCheck>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            repo_path: String::new(),
+            target_path: None,
+            checkout: None,
+            init: false,
+            force: false,
+            cache_list: false,
+            cache_order: CacheOrder::Blob,
+            file: None,
+            write: false,
+            config_from_repo: false,
+            branches: vec![DEFAULT_BRANCH.to_string()],
+            branches_given: false,
+            rev: None,
+            on_source_moved: None,
+            cache_path: "cache.tsv".to_string(),
+            max_cache_age_days: None,
+            cache_append: false,
+            html_extensions: vec!["html".to_string()],
+            xhtml_extensions: vec!["xhtml".to_string()],
+            xml_extensions: vec!["xml".to_string()],
+            // Source maps are large JSON, they compress well.
+            compress_extensions: vec!["map".to_string()],
+            passthrough_extensions: vec!["png".to_string(), "jpg".to_string(), "wasm".to_string()],
+            // MkDocs includes the theme because I put it in a subdirectory of
+            // the docs, but it really shouldn't be there.
+            skip_dirs: vec!["theme".to_string()],
+            zopfli_iterations: ZOPFLI_ITERATIONS_DEFAULT,
+            zopfli_adaptive: false,
+            blob_timeout_secs: None,
+            brotli_level: BROTLI_LEVEL_DEFAULT,
+            brotli_levels: BTreeMap::new(),
+            brotli_modes: BTreeMap::new(),
+            banner: DEFAULT_BANNER.to_string(),
+            manifest_path: None,
+            fail_on_growth: false,
+            budget_bytes: None,
+            fail_on_budget: false,
+            dump_decompressed_dir: None,
+            inline_max_bytes: None,
+            hardlink_duplicates: false,
+            write_files: false,
+            deflate: false,
+            no_drop: false,
+            drop_unrecognized: false,
+            drop_source_maps: false,
+            require: Vec::new(),
+            sniff_content: false,
+            no_plain: false,
+            alias_pages: None,
+            threads_walk: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            threads_compress: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            reproducible: false,
+            check_determinism: false,
+            badge_path: None,
+            strict: false,
+            max_asset_bytes: None,
+            histogram: false,
+            measure_decode: false,
+            count_assets: false,
+            minifiers: BTreeMap::new(),
+            content_types: BTreeMap::new(),
+            keep: Keep::Both,
+            verify_cache: false,
+            verify_writes: false,
+            only_changed_extensions: false,
+            layout_gz: Some("{path}.gz".to_string()),
+            layout_br: Some("{path}.br".to_string()),
+            layout_deflate: Some("{path}.deflate".to_string()),
+            diff_tree: false,
+            print_tree: false,
+            min_savings_pct: None,
+            metrics_path: None,
+            log_jsonl: None,
+            force_overwrite: false,
+            commit_to_branch: false,
+            output_s3: None,
+            strip_meta: Vec::new(),
+            no_minify: Vec::new(),
+            minify_js: false,
+            preset: Preset::Production,
+            normalize_newlines: None,
+            limit: None,
+            dry_run: false,
+            list_dropped: false,
+            list_misses: false,
+            subdir: None,
+            stats_only: false,
+            sanity_checks: Vec::new(),
+        }
+    }
+}
+
+/// The contents of a `minimizer.toml` config file.
+///
+/// Every key is optional, keys that are absent leave the default in place.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    branches: Option<Vec<String>>,
+    rev: Option<String>,
+    on_source_moved: Option<String>,
+    cache: Option<String>,
+    max_cache_age: Option<u64>,
+    cache_append: Option<bool>,
+    html_extensions: Option<Vec<String>>,
+    xhtml_extensions: Option<Vec<String>>,
+    xml_extensions: Option<Vec<String>>,
+    compress_extensions: Option<Vec<String>>,
+    passthrough_extensions: Option<Vec<String>>,
+    skip_dirs: Option<Vec<String>>,
+    zopfli_iterations: Option<u8>,
+    zopfli_adaptive: Option<bool>,
+    blob_timeout: Option<u64>,
+    brotli_level: Option<u32>,
+    banner: Option<String>,
+    banner_file: Option<String>,
+    manifest: Option<String>,
+    fail_on_growth: Option<bool>,
+    budget_bytes: Option<usize>,
+    fail_on_budget: Option<bool>,
+    inline_max_bytes: Option<usize>,
+    hardlink_duplicates: Option<bool>,
+    write_files: Option<bool>,
+    deflate: Option<bool>,
+    no_drop: Option<bool>,
+    drop_unrecognized: Option<bool>,
+    drop_source_maps: Option<bool>,
+    require: Option<Vec<String>>,
+    sniff_content: Option<bool>,
+    no_plain: Option<bool>,
+    alias_pages: Option<String>,
+    jobs: Option<usize>,
+    threads_walk: Option<usize>,
+    threads_compress: Option<usize>,
+    reproducible: Option<bool>,
+    check_determinism: Option<bool>,
+    badge: Option<String>,
+    strict: Option<bool>,
+    max_asset_bytes: Option<usize>,
+    histogram: Option<bool>,
+    measure_decode: Option<bool>,
+    count_assets: Option<bool>,
+    minifiers: Option<BTreeMap<String, String>>,
+    content_types: Option<BTreeMap<String, String>>,
+    brotli_levels: Option<BTreeMap<String, u32>>,
+    brotli_modes: Option<BTreeMap<String, String>>,
+    prefer: Option<String>,
+    keep_smallest: Option<bool>,
+    verify_cache: Option<bool>,
+    verify_writes: Option<bool>,
+    only_changed_extensions: Option<bool>,
+    layout: Option<String>,
+    layout_gz: Option<String>,
+    layout_br: Option<String>,
+    layout_deflate: Option<String>,
+    diff_tree: Option<bool>,
+    print_tree: Option<bool>,
+    min_savings_pct: Option<u32>,
+    metrics: Option<String>,
+    log_jsonl: Option<String>,
+    output_s3: Option<String>,
+    strip_meta: Option<Vec<String>>,
+    no_minify: Option<Vec<String>>,
+    minify_js: Option<bool>,
+    preset: Option<String>,
+    normalize_newlines: Option<String>,
+    subdir: Option<String>,
+    list_dropped: Option<bool>,
+    list_misses: Option<bool>,
+    limit: Option<usize>,
+    dry_run: Option<bool>,
+    stats_only: Option<bool>,
+    dump_decompressed: Option<String>,
+    sanity_checks: Option<Vec<String>>,
+}
+
+impl ConfigFile {
+    /// Load and parse the config file at the given path.
+    fn load(fname: &str) -> Self {
+        let contents = fs::read_to_string(fname)
+            .unwrap_or_else(|err| panic!("Failed to read config file {}: {}", fname, err));
+        Self::parse(&contents, fname)
+    }
+
+    /// Parse the contents of a config file, `fname` is for error messages.
+    fn parse(contents: &str, fname: &str) -> Self {
+        toml::from_str(contents)
+            .unwrap_or_else(|err| panic!("Invalid config file {}: {}", fname, err))
+    }
+
+    /// Panic if the file sets a key that the config in a branch may not set.
+    ///
+    /// Whoever can push to the branch should not be able to run commands on
+    /// the build machine with `minifiers`, or to write files outside of the
+    /// tree, or upload it elsewhere. Those keys must come from flags.
+    fn check_from_repo(&self, fname: &str) {
+        let keys = [
+            ("minifiers", self.minifiers.is_some()),
+            ("banner_file", self.banner_file.is_some()),
+            ("cache", self.cache.is_some()),
+            ("manifest", self.manifest.is_some()),
+            ("badge", self.badge.is_some()),
+            ("metrics", self.metrics.is_some()),
+            ("log_jsonl", self.log_jsonl.is_some()),
+            ("output_s3", self.output_s3.is_some()),
+            ("dump_decompressed", self.dump_decompressed.is_some()),
+        ];
+        for (key, is_set) in keys {
+            assert!(
+                !is_set,
+                "{} can't set {}, it runs commands or uses files outside the tree, use a flag.",
+                fname, key,
+            );
+        }
+    }
+
+    /// Override the options with the values that are set in the file.
+    fn apply(self, opts: &mut Options) {
+        fn set<T>(target: &mut T, value: Option<T>) {
+            if let Some(v) = value {
+                *target = v;
+            }
+        }
+        opts.branches_given |= self.branches.is_some();
+        set(&mut opts.branches, self.branches);
+        set(&mut opts.rev, self.rev.map(Some));
+        set(
+            &mut opts.on_source_moved,
+            self.on_source_moved.map(|value| Some(parse_source_moved(&value))),
+        );
+        set(&mut opts.cache_path, self.cache);
+        set(&mut opts.max_cache_age_days, self.max_cache_age.map(Some));
+        set(&mut opts.cache_append, self.cache_append);
+        set(&mut opts.html_extensions, self.html_extensions);
+        set(&mut opts.xhtml_extensions, self.xhtml_extensions);
+        set(&mut opts.xml_extensions, self.xml_extensions);
+        set(&mut opts.compress_extensions, self.compress_extensions);
+        set(&mut opts.passthrough_extensions, self.passthrough_extensions);
+        set(&mut opts.skip_dirs, self.skip_dirs);
+        set(&mut opts.zopfli_iterations, self.zopfli_iterations);
+        set(&mut opts.zopfli_adaptive, self.zopfli_adaptive);
+        set(&mut opts.blob_timeout_secs, self.blob_timeout.map(Some));
+        set(&mut opts.brotli_level, self.brotli_level);
+        set(&mut opts.banner, self.banner);
+        set(&mut opts.banner, self.banner_file.as_deref().map(read_banner));
+        set(&mut opts.fail_on_growth, self.fail_on_growth);
+        set(&mut opts.fail_on_budget, self.fail_on_budget);
+        set(&mut opts.budget_bytes, self.budget_bytes.map(Some));
+        set(&mut opts.manifest_path, self.manifest.map(Some));
+        set(&mut opts.inline_max_bytes, self.inline_max_bytes.map(Some));
+        set(&mut opts.hardlink_duplicates, self.hardlink_duplicates);
+        set(&mut opts.write_files, self.write_files);
+        set(&mut opts.deflate, self.deflate);
+        set(&mut opts.no_drop, self.no_drop);
+        set(&mut opts.drop_unrecognized, self.drop_unrecognized);
+        set(&mut opts.drop_source_maps, self.drop_source_maps);
+        set(&mut opts.require, self.require);
+        set(&mut opts.sniff_content, self.sniff_content);
+        set(&mut opts.no_plain, self.no_plain);
+        set(&mut opts.alias_pages, self.alias_pages.map(|value| Some(parse_page_alias(&value))));
+        set(&mut opts.threads_walk, self.jobs);
+        set(&mut opts.threads_compress, self.jobs);
+        set(&mut opts.threads_walk, self.threads_walk);
+        set(&mut opts.threads_compress, self.threads_compress);
+        set(&mut opts.reproducible, self.reproducible);
+        set(&mut opts.check_determinism, self.check_determinism);
+        set(&mut opts.badge_path, self.badge.map(Some));
+        set(&mut opts.strict, self.strict);
+        set(&mut opts.max_asset_bytes, self.max_asset_bytes.map(Some));
+        set(&mut opts.histogram, self.histogram);
+        set(&mut opts.measure_decode, self.measure_decode);
+        set(&mut opts.count_assets, self.count_assets);
+        set(&mut opts.minifiers, self.minifiers);
+        set(&mut opts.content_types, self.content_types);
+        set(&mut opts.brotli_levels, self.brotli_levels);
+        set(
+            &mut opts.brotli_modes,
+            self.brotli_modes.map(|modes| {
+                modes.into_iter().map(|(ext, mode)| (ext, parse_brotli_mode(&mode))).collect()
+            }),
+        );
+        set(&mut opts.keep, self.prefer.as_deref().map(parse_prefer));
+        set(&mut opts.verify_cache, self.verify_cache);
+        set(&mut opts.verify_writes, self.verify_writes);
+        set(&mut opts.only_changed_extensions, self.only_changed_extensions);
+        if let Some(layout) = self.layout {
+            (opts.layout_gz, opts.layout_br, opts.layout_deflate) = parse_layout(&layout);
+        }
+        set(&mut opts.layout_gz, self.layout_gz.map(parse_template));
+        set(&mut opts.layout_br, self.layout_br.map(parse_template));
+        set(&mut opts.layout_deflate, self.layout_deflate.map(parse_template));
+        set(&mut opts.diff_tree, self.diff_tree);
+        set(&mut opts.print_tree, self.print_tree);
+        set(&mut opts.min_savings_pct, self.min_savings_pct.map(Some));
+        set(&mut opts.metrics_path, self.metrics.map(Some));
+        set(&mut opts.log_jsonl, self.log_jsonl.map(Some));
+        set(&mut opts.output_s3, self.output_s3.map(Some));
+        set(&mut opts.strip_meta, self.strip_meta);
+        set(&mut opts.no_minify, self.no_minify);
+        set(&mut opts.minify_js, self.minify_js);
+        set(&mut opts.preset, self.preset.as_deref().map(parse_preset));
+        set(
+            &mut opts.normalize_newlines,
+            self.normalize_newlines.map(|value| Some(parse_trailing_newline(&value))),
+        );
+        set(&mut opts.subdir, self.subdir.map(Some));
+        // A dry run lists what it drops, unless the file says otherwise.
+        if self.dry_run == Some(true) {
+            opts.list_dropped = true;
+        }
+        set(&mut opts.dry_run, self.dry_run);
+        set(&mut opts.list_dropped, self.list_dropped);
+        set(&mut opts.list_misses, self.list_misses);
+        set(&mut opts.limit, self.limit.map(Some));
+        set(&mut opts.stats_only, self.stats_only);
+        set(&mut opts.dump_decompressed_dir, self.dump_decompressed.map(Some));
+        if let Some(names) = self.sanity_checks {
+            opts.sanity_checks = names.iter().map(|name| parse_sanity_check(name)).collect();
+        }
+        if self.keep_smallest == Some(true) {
+            opts.keep = Keep::Smallest;
+        }
+    }
+}
+
+/// Split a comma-separated list, an empty string is an empty list.
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .filter(|part| !part.is_empty())
+        .map(|part| part.to_string())
+        .collect()
+}
+
+/// Parse a number, or panic with a message that names the flag.
+fn parse_number<T: std::str::FromStr>(flag: &str, value: &str) -> T {
+    value
+        .parse()
+        .unwrap_or_else(|_| panic!("Expected a number after {}, got {}.", flag, value))
+}
+
+/// Write a config file with the default settings, for the `init` subcommand.
+///
+/// Exits with an error if the file exists already, unless `force` is set.
+pub fn init_config(force: bool) {
+    if !force && Path::new(CONFIG_PATH_DEFAULT).exists() {
+        println!("{} exists already, pass --force to overwrite it.", CONFIG_PATH_DEFAULT);
+        std::process::exit(1);
+    }
+    let contents = default_config(&Options::default());
+    fs::write(CONFIG_PATH_DEFAULT, contents)
+        .unwrap_or_else(|err| panic!("Failed to write {}: {}", CONFIG_PATH_DEFAULT, err));
+    println!("Wrote {}, see the readme for the other keys.", CONFIG_PATH_DEFAULT);
+}
+
+/// Return the contents of a config file with the settings in `opts`.
+///
+/// Lists format with `Debug`, which for plain strings is valid TOML too.
+fn default_config(opts: &Options) -> String {
+    // Writing the default branch would make it explicit, and then we would no
+    // longer fall back to HEAD when it does not exist.
+    let branches = match opts.branches_given {
+        true => format!("branches = {:?}", opts.branches),
+        false => format!(
+            "# Without this key, Minimizer uses HEAD if there is no {} branch.\n# branches = {:?}",
+            DEFAULT_BRANCH, opts.branches,
+        ),
+    };
+    format!(
+        "\
+# Config for Minimizer. Flags on the command line override these keys. All keys
+# are optional, see the readme for what else can go here.
+
+# The branches to minimize, and where to keep minimized blobs between runs.
+{branches}
+cache = {cache:?}
+
+# Which files are html, xhtml, and xml pages, by extension. When a file matches
+# none of the lists, the run fails, unless drop_unrecognized is true.
+html_extensions = {html:?}
+xhtml_extensions = {xhtml:?}
+xml_extensions = {xml:?}
+# Files to compress but not minify, and files to copy as they are.
+compress_extensions = {compress:?}
+passthrough_extensions = {passthrough:?}
+drop_unrecognized = {drop_unrecognized}
+
+# Directories to leave out of the minimized tree.
+skip_dirs = {skip_dirs:?}
+
+# Compression levels. More Zopfli iterations make Gzip files slightly smaller,
+# at the cost of time. The Brotli level goes up to 11.
+zopfli_iterations = {zopfli_iterations}
+brotli_level = {brotli_level}
+deflate = {deflate}
+",
+        branches = branches,
+        cache = opts.cache_path,
+        html = opts.html_extensions,
+        xhtml = opts.xhtml_extensions,
+        xml = opts.xml_extensions,
+        compress = opts.compress_extensions,
+        passthrough = opts.passthrough_extensions,
+        drop_unrecognized = opts.drop_unrecognized,
+        skip_dirs = opts.skip_dirs,
+        zopfli_iterations = opts.zopfli_iterations,
+        brotli_level = opts.brotli_level,
+        deflate = opts.deflate,
+    )
+}
+
+/// Override the options with the `MINIMIZER_*` environment variables that are set.
+fn apply_env(opts: &mut Options) {
+    if let Ok(cache) = env::var("MINIMIZER_CACHE") {
+        opts.cache_path = cache;
+    }
+    if let Ok(branch) = env::var("MINIMIZER_BRANCH") {
+        opts.branches = parse_list(&branch);
+        opts.branches_given = true;
+    }
+    if let Ok(jobs) = env::var("MINIMIZER_JOBS") {
+        opts.threads_walk = parse_number("MINIMIZER_JOBS", &jobs);
+        opts.threads_compress = opts.threads_walk;
+    }
+}
+
+impl Options {
+    /// Parse options from the command-line arguments, excluding program name.
+    ///
+    /// Options are read from the config file first, if there is one, then from
+    /// environment variables, and flags on the command line override both.
+    pub fn parse<I: Iterator<Item = String>>(args: I) -> Self {
+        Self::parse_with(args, RepoConfig::default())
+    }
+
+    /// Like [`Options::parse`], but with the config from the source branch.
+    ///
+    /// The config file in the branch takes the place of the local one, and
+    /// the banner in the branch that of the config. Flags still come last.
+    pub fn parse_with<I: Iterator<Item = String>>(args: I, repo_config: RepoConfig) -> Self {
+        let args: Vec<String> = args.collect();
+        let mut opts = Self::default();
+
+        let config_flag = args.iter().position(|arg| arg == "--config");
+        match (repo_config.config, config_flag) {
+            (Some(contents), _) => {
+                let fname = format!("{}/config.toml", REPO_CONFIG_DIR);
+                let config = ConfigFile::parse(&contents, &fname);
+                config.check_from_repo(&fname);
+                config.apply(&mut opts);
+            }
+            (None, Some(i)) => {
+                let fname = args.get(i + 1).expect("Expected a value after --config.");
+                ConfigFile::load(fname).apply(&mut opts);
+            }
+            (None, None) if Path::new(CONFIG_PATH_DEFAULT).is_file() => {
+                ConfigFile::load(CONFIG_PATH_DEFAULT).apply(&mut opts);
+            }
+            (None, None) => {}
+        }
+        if let Some(banner) = repo_config.banner {
+            opts.banner = banner;
+        }
+        apply_env(&mut opts);
+
+        let mut positional = Vec::new();
+        let mut include_theme = false;
+        let mut no_banner = false;
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let mut value = |flag: &str| {
+                args.next()
+                    .unwrap_or_else(|| panic!("Expected a value after {}.", flag))
+            };
+            match arg.as_str() {
+                // Already handled above, but we still need to skip the value.
+                "--config" => drop(value("--config")),
+                "--config-from-repo" => opts.config_from_repo = true,
+                "--force" => opts.force = true,
+                "--sort" => opts.cache_order = parse_cache_order(&value("--sort")),
+                "--write" => opts.write = true,
+                "--branch" => {
+                    opts.branches = parse_list(&value("--branch"));
+                    opts.branches_given = true;
+                }
+                "--rev" => opts.rev = Some(value("--rev")),
+                "--on-source-moved" => {
+                    opts.on_source_moved = Some(parse_source_moved(&value("--on-source-moved")))
+                }
+                "--subdir" => opts.subdir = Some(value("--subdir")),
+                "--cache" => opts.cache_path = value("--cache"),
+                "--cache-append" => opts.cache_append = true,
+                "--max-cache-age" => {
+                    opts.max_cache_age_days =
+                        Some(parse_number("--max-cache-age", &value("--max-cache-age")))
+                }
+                "--verify-cache" => opts.verify_cache = true,
+                "--verify-writes" => opts.verify_writes = true,
+                "--only-changed-extensions" => opts.only_changed_extensions = true,
+                "--html-extensions" => {
+                    opts.html_extensions = parse_list(&value("--html-extensions"))
+                }
+                "--xhtml-extensions" => {
+                    opts.xhtml_extensions = parse_list(&value("--xhtml-extensions"))
+                }
+                "--xml-extensions" => opts.xml_extensions = parse_list(&value("--xml-extensions")),
+                "--compress-extensions" => {
+                    opts.compress_extensions = parse_list(&value("--compress-extensions"))
+                }
+                "--passthrough-extensions" => {
+                    opts.passthrough_extensions = parse_list(&value("--passthrough-extensions"))
+                }
+                "--minifier" => {
+                    let minifier = value("--minifier");
+                    let (ext, command) = minifier.split_once('=').unwrap_or_else(|| {
+                        panic!("Expected ext=command after --minifier, got {}.", minifier)
+                    });
+                    opts.minifiers.insert(ext.to_string(), command.to_string());
+                }
+                "--content-type" => {
+                    let content_type = value("--content-type");
+                    let (pattern, ext) = content_type.split_once('=').unwrap_or_else(|| {
+                        panic!("Expected path=ext after --content-type, got {}.", content_type)
+                    });
+                    opts.content_types.insert(pattern.to_string(), ext.to_string());
+                }
+                "--no-drop" => opts.no_drop = true,
+                "--drop-unrecognized" => opts.drop_unrecognized = true,
+                "--drop-source-maps" => opts.drop_source_maps = true,
+                "--require" => opts.require.push(value("--require")),
+                "--sniff-content" => opts.sniff_content = true,
+                "--no-plain" => opts.no_plain = true,
+                "--alias-pages" => {
+                    opts.alias_pages = Some(parse_page_alias(&value("--alias-pages")))
+                }
+                "--max-asset-bytes" => {
+                    opts.max_asset_bytes =
+                        Some(parse_number("--max-asset-bytes", &value("--max-asset-bytes")))
+                }
+                "--skip-dirs" => opts.skip_dirs = parse_list(&value("--skip-dirs")),
+                "--include-theme" => include_theme = true,
+                "--zopfli-iterations" => {
+                    opts.zopfli_iterations =
+                        parse_number("--zopfli-iterations", &value("--zopfli-iterations"))
+                }
+                "--zopfli-adaptive" => opts.zopfli_adaptive = true,
+                "--blob-timeout" => {
+                    opts.blob_timeout_secs =
+                        Some(parse_number("--blob-timeout", &value("--blob-timeout")))
+                }
+                "--limit" => opts.limit = Some(parse_number("--limit", &value("--limit"))),
+                "--dry-run" => {
+                    opts.dry_run = true;
+                    opts.list_dropped = true;
+                }
+                "--list-dropped" => opts.list_dropped = true,
+                "--list-misses" => opts.list_misses = true,
+                "--stats-only" => opts.stats_only = true,
+                "--jobs" => {
+                    opts.threads_walk = parse_number("--jobs", &value("--jobs"));
+                    opts.threads_compress = opts.threads_walk;
+                }
+                "--threads-walk" => {
+                    opts.threads_walk = parse_number("--threads-walk", &value("--threads-walk"))
+                }
+                "--threads-compress" => {
+                    opts.threads_compress =
+                        parse_number("--threads-compress", &value("--threads-compress"))
+                }
+                "--reproducible" => opts.reproducible = true,
+                "--check-determinism" => opts.check_determinism = true,
+                "--deflate" => opts.deflate = true,
+                "--prefer" => opts.keep = parse_prefer(&value("--prefer")),
+                "--keep-smallest" => opts.keep = Keep::Smallest,
+                "--min-savings-pct" => {
+                    opts.min_savings_pct =
+                        Some(parse_number("--min-savings-pct", &value("--min-savings-pct")))
+                }
+                "--layout" => {
+                    (opts.layout_gz, opts.layout_br, opts.layout_deflate) =
+                        parse_layout(&value("--layout"))
+                }
+                "--layout-gz" => opts.layout_gz = parse_template(value("--layout-gz")),
+                "--layout-br" => opts.layout_br = parse_template(value("--layout-br")),
+                "--layout-deflate" => {
+                    opts.layout_deflate = parse_template(value("--layout-deflate"))
+                }
+                "--brotli-level" => {
+                    // Either a level for all files, or for some extensions, or both.
+                    for level in parse_list(&value("--brotli-level")) {
+                        match level.split_once('=') {
+                            Some((ext, level)) => {
+                                let level = parse_number("--brotli-level", level);
+                                opts.brotli_levels.insert(ext.to_string(), level);
+                            }
+                            None => opts.brotli_level = parse_number("--brotli-level", &level),
+                        }
+                    }
+                }
+                "--brotli-mode" => {
+                    for mode in parse_list(&value("--brotli-mode")) {
+                        let (ext, mode) = mode.split_once('=').unwrap_or_else(|| {
+                            panic!("Expected ext=mode after --brotli-mode, got {}.", mode)
+                        });
+                        opts.brotli_modes.insert(ext.to_string(), parse_brotli_mode(mode));
+                    }
+                }
+                "--strict" => opts.strict = true,
+                "--strip-meta" => opts.strip_meta.push(value("--strip-meta")),
+                "--no-minify" => opts.no_minify.push(value("--no-minify")),
+                "--minify-js" => opts.minify_js = true,
+                "--preset" => opts.preset = parse_preset(&value("--preset")),
+                "--normalize-newlines" => {
+                    let trailing = parse_trailing_newline(&value("--normalize-newlines"));
+                    opts.normalize_newlines = Some(trailing);
+                }
+                "--sanity-check" => {
+                    opts.sanity_checks = parse_list(&value("--sanity-check"))
+                        .iter()
+                        .map(|name| parse_sanity_check(name))
+                        .collect()
+                }
+                "--banner-file" => opts.banner = read_banner(&value("--banner-file")),
+                "--no-banner" => no_banner = true,
+                "--manifest" => opts.manifest_path = Some(value("--manifest")),
+                "--histogram" => opts.histogram = true,
+                "--measure-decode" => opts.measure_decode = true,
+                "--count-assets" => opts.count_assets = true,
+                "--metrics" => opts.metrics_path = Some(value("--metrics")),
+                "--log-jsonl" => opts.log_jsonl = Some(value("--log-jsonl")),
+                "--badge" => opts.badge_path = Some(value("--badge")),
+                "--diff-tree" => opts.diff_tree = true,
+                "--print-tree" => opts.print_tree = true,
+                "--fail-on-growth" => opts.fail_on_growth = true,
+                "--budget-bytes" => {
+                    opts.budget_bytes =
+                        Some(parse_number("--budget-bytes", &value("--budget-bytes")))
+                }
+                "--fail-on-budget" => opts.fail_on_budget = true,
+                "--inline-max-bytes" => {
+                    opts.inline_max_bytes =
+                        Some(parse_number("--inline-max-bytes", &value("--inline-max-bytes")))
+                }
+                "--force-overwrite" => opts.force_overwrite = true,
+                "--commit-to-branch" => opts.commit_to_branch = true,
+                "--output-s3" => opts.output_s3 = Some(value("--output-s3")),
+                "--hardlink-duplicates" => opts.hardlink_duplicates = true,
+                "--write-files" => opts.write_files = true,
+                "--dump-decompressed" => {
+                    opts.dump_decompressed_dir = Some(value("--dump-decompressed"))
+                }
+                "--help" | "-h" => {
+                    println!("{}", USAGE);
+                    std::process::exit(0);
+                }
+                "--report-schema" => {
+                    println!("{}", crate::report::LOG_SCHEMA);
+                    std::process::exit(0);
+                }
+                flag if flag.starts_with("--") => panic!("Unknown option: {}", flag),
+                _ => positional.push(arg),
+            }
+        }
+
+        // Apply this after the loop, so it does not depend on the order of
+        // --include-theme and --skip-dirs.
+        if include_theme {
+            opts.skip_dirs.retain(|dir| dir != "theme");
+        }
+        // Likewise, --no-banner wins over --banner-file and the repo banner.
+        if no_banner {
+            opts.banner.clear();
+        }
+
+        // Staging keeps scripts readable, there is nothing to report about them.
+        if opts.preset == Preset::Staging {
+            opts.minify_js = false;
+        }
+
+        // The output does not depend on the number of threads, but with a single
+        // thread there is not even a question about it. For the limit it does
+        // matter, the pages that we minimize should be the first in the tree.
+        // With --stats-only, objects only exist in memory of the main thread.
+        if opts.reproducible || opts.limit.is_some() || opts.stats_only {
+            opts.threads_walk = 1;
+        }
+
+        assert!(
+            opts.budget_bytes.is_some() || !opts.fail_on_budget,
+            "--fail-on-budget requires --budget-bytes.",
+        );
+        assert!(!opts.branches.is_empty(), "Expected at least one branch.");
+        assert!(
+            !opts.check_determinism || opts.blob_timeout_secs.is_none(),
+            "--check-determinism can't be combined with --blob-timeout, \
+            the fast fallback would count as a difference.",
+        );
+        assert!(
+            opts.branches.len() == 1 || opts.branches.iter().all(|b| !b.contains('/')),
+            "Branches must not contain a slash when minimizing multiple branches.",
+        );
+        assert!(
+            opts.minifiers.values().all(|command| !command.trim().is_empty()),
+            "Minifier commands must not be empty.",
+        );
+        assert!(
+            !opts.cache_append || !opts.cache_path.ends_with(".gz"),
+            "--cache-append does not work with a gzipped cache.",
+        );
+        assert!(
+            !opts.cache_append || opts.max_cache_age_days.is_none(),
+            "--cache-append can't be combined with --max-cache-age, it only writes new entries.",
+        );
+        assert!(
+            opts.min_savings_pct.map_or(true, |pct| pct <= 100),
+            "--min-savings-pct must be at most 100.",
+        );
+        assert!(
+            !opts.no_plain
+                || opts.layout_gz.is_some()
+                || opts.layout_br.is_some()
+                || (opts.deflate && opts.layout_deflate.is_some()),
+            "--no-plain requires a compressed version in the tree, but the layout has none.",
+        );
+        assert!(
+            !opts.commit_to_branch
+                || (opts.rev.is_none() && opts.branches.len() == 1 && opts.subdir.is_none()),
+            "--commit-to-branch requires a single branch, and no --rev or --subdir.",
+        );
+        // The next run minimizes the branch with our Gzip and Brotli files in
+        // it. They match no extension list, so they must be dropped.
+        assert!(
+            !opts.commit_to_branch || (opts.drop_unrecognized && !opts.no_drop),
+            "--commit-to-branch requires --drop-unrecognized, and no --no-drop.",
+        );
+        assert!(opts.threads_walk > 0, "Need at least one thread to walk.");
+        assert!(opts.threads_compress > 0, "Need at least one thread to compress.");
+        assert!(opts.zopfli_iterations > 0, "Zopfli needs at least one iteration.");
+        assert!(
+            opts.brotli_levels.values().chain([&opts.brotli_level]).all(|level| *level <= 11),
+            "Brotli level must be at most 11.",
+        );
+
+        let mut positional = positional.into_iter().peekable();
+        // These subcommands don't need a repository.
+        let commands = ["init", "cache-list", "file"];
+        if let Some(command) = positional.next_if(|arg| commands.contains(&arg.as_str())) {
+            if command == "file" {
+                opts.file = Some(
+                    positional
+                        .next()
+                        .unwrap_or_else(|| exit_with_usage("Expected the file to minify.")),
+                );
+            }
+            if let Some(extra) = positional.next() {
+                exit_with_usage(&format!("Unexpected argument: {}", extra));
+            }
+            opts.init = command == "init";
+            opts.cache_list = command == "cache-list";
+            return opts;
+        }
+        let is_checkout = positional.next_if_eq("checkout").is_some();
+        let mut expect = |what: &str| {
+            positional
+                .next()
+                .unwrap_or_else(|| exit_with_usage(&format!("Expected {}.", what)))
+        };
+        opts.repo_path = expect("the path of the input repository");
+        if is_checkout {
+            opts.checkout = Some(expect("the tree to check out"));
+            opts.target_path = Some(expect("the output directory"));
+        } else {
+            opts.target_path = positional.next();
+        }
+        if let Some(extra) = positional.next() {
+            exit_with_usage(&format!("Unexpected argument: {}", extra));
+        }
+
+        opts
+    }
+
+    /// Return whether a page is larger than the budget, if there is one.
+    pub fn exceeds_budget(&self, sizes: &Sizes) -> bool {
+        match self.budget_bytes {
+            Some(budget) => sizes.br_len > budget,
+            None => false,
+        }
+    }
+
+    /// Print which files we minify and which ones we copy, by extension.
+    pub fn print_extensions(&self) {
+        let minifiers: Vec<&str> = self.minifiers.keys().map(|ext| ext.as_str()).collect();
+        let lists = [
+            ("HTML", self.html_extensions.join(",")),
+            ("XHTML", self.xhtml_extensions.join(",")),
+            ("XML", self.xml_extensions.join(",")),
+            ("External minifier", minifiers.join(",")),
+            ("Compress only", self.compress_extensions.join(",")),
+            ("Passthrough", self.passthrough_extensions.join(",")),
+            ("Skipped directories", self.skip_dirs.join(",")),
+        ];
+        for (label, list) in lists {
+            if !list.is_empty() {
+                println!("  {}: {}", label, list);
+            }
+        }
+        if self.no_drop {
+            println!("  Other files are copied, they are not dropped.");
+        }
+    }
+
+    /// Return whether to keep the Gzip and the Brotli version of a page.
+    pub fn keep_compressed(&self, sizes: &Sizes) -> (bool, bool) {
+        let (keep_gz, keep_br) = self.keep_by_preference(sizes);
+        (
+            keep_gz && self.saves_enough(sizes.minified_len, sizes.gz_len),
+            keep_br && self.saves_enough(sizes.minified_len, sizes.br_len),
+        )
+    }
+
+    /// Return the branches to minimize, or the revision if we got `--rev`.
+    pub fn sources(&self) -> &[String] {
+        match &self.rev {
+            Some(rev) => std::slice::from_ref(rev),
+            None => &self.branches[..],
+        }
+    }
+
+    /// Return whether to put the minified page itself in the tree.
+    ///
+    /// With `--no-plain` we leave it out, unless it is the only version left.
+    pub fn keep_plain(&self, sizes: &Sizes) -> bool {
+        let (keep_gz, keep_br) = self.keep_compressed(sizes);
+        let has_compressed = (keep_gz && self.layout_gz.is_some())
+            || (keep_br && self.layout_br.is_some())
+            || (self.deflate && self.layout_deflate.is_some());
+        !self.no_plain || !has_compressed
+    }
+
+    /// Return whether a compressed version is at least `--min-savings-pct`
+    /// smaller than the minified page.
+    fn saves_enough(&self, minified_len: usize, compressed_len: usize) -> bool {
+        match self.min_savings_pct {
+            Some(pct) => compressed_len * 100 <= minified_len * (100 - pct as usize),
+            None => true,
+        }
+    }
+
+    /// Return which versions to keep by `--prefer` or `--keep-smallest`.
+    fn keep_by_preference(&self, sizes: &Sizes) -> (bool, bool) {
+        match self.keep {
+            Keep::Both => (true, true),
+            Keep::Gzip => (true, false),
+            Keep::Brotli => (false, true),
+            Keep::Smallest if sizes.gz_len < sizes.br_len => (true, false),
+            Keep::Smallest => (false, true),
+        }
+    }
+
+    /// Return the name that decides how we treat the file at `path`.
+    ///
+    /// That is the file name, or if `--content-type` has a pattern that
+    /// matches the path, the file name with that extension appended.
+    pub fn classify_name<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        let name = path.rsplit_once('/').map_or(path, |(_, name)| name);
+        match self.content_types.iter().find(|(pattern, _)| glob_matches(pattern, path)) {
+            Some((_, ext)) => Cow::Owned(format!("{}.{}", name, ext)),
+            None => Cow::Borrowed(name),
+        }
+    }
+
+    /// Return whether the page at `path` matches a `--no-minify` pattern.
+    pub fn is_no_minify(&self, path: &str) -> bool {
+        self.no_minify.iter().any(|pattern| glob_matches(pattern, path))
+    }
+
+    /// Return whether the file is a page, that we minify and compress.
+    pub fn is_page(&self, name: &str) -> bool {
+        self.is_html(name)
+            || self.is_xhtml(name)
+            || self.is_xml(name)
+            || self.is_compress_only(name)
+            || self.external_minifier(name).is_some()
+    }
+
+    /// Return whether the file name has one of the XML extensions.
+    pub fn is_xml(&self, name: &str) -> bool {
+        has_extension(name, &self.xml_extensions)
+    }
+
+    /// Return whether the file name has one of the compress-only extensions.
+    pub fn is_compress_only(&self, name: &str) -> bool {
+        has_extension(name, &self.compress_extensions)
+    }
+
+    /// Return whether the file name has one of the html extensions.
+    pub fn is_html(&self, name: &str) -> bool {
+        has_extension(name, &self.html_extensions)
+    }
+
+    /// Return the Brotli level for the file name.
+    pub fn brotli_level(&self, name: &str) -> u32 {
+        let ext = name.rsplit_once('.').map(|(_, ext)| ext);
+        ext.and_then(|ext| self.brotli_levels.get(ext)).copied().unwrap_or(self.brotli_level)
+    }
+
+    /// Return the Brotli mode for the file name.
+    ///
+    /// Pages and the usual text assets are text, everything else is generic,
+    /// unless `--brotli-mode` says otherwise.
+    pub fn brotli_mode(&self, name: &str) -> BrotliMode {
+        let ext = name.rsplit_once('.').map(|(_, ext)| ext);
+        if let Some(mode) = ext.and_then(|ext| self.brotli_modes.get(ext)) {
+            return *mode;
+        }
+        let is_markup = self.is_html(name) || self.is_xhtml(name) || self.is_xml(name);
+        if is_markup || ext.map_or(false, |ext| BROTLI_TEXT_EXTENSIONS.contains(&ext)) {
+            BrotliMode::Text
+        } else {
+            BrotliMode::Generic
+        }
+    }
+
+    /// Return the external minifier command for the file name, if there is one.
+    pub fn external_minifier(&self, name: &str) -> Option<&str> {
+        let (_, ext) = name.rsplit_once('.')?;
+        self.minifiers.get(ext).map(|command| command.as_str())
+    }
+
+    /// Return whether the file name has one of the XHTML extensions.
+    pub fn is_xhtml(&self, name: &str) -> bool {
+        has_extension(name, &self.xhtml_extensions)
+    }
+
+    /// Return whether the file name has one of the passthrough extensions.
+    pub fn is_passthrough(&self, name: &str) -> bool {
+        has_extension(name, &self.passthrough_extensions)
+    }
+}
+
+/// Return whether the path matches the pattern.
+///
+/// In the pattern, `*` matches any part of a file or directory name, `?` one
+/// character of it, and `**` matches across slashes. Paths are relative to the
+/// root of the site, so a leading slash is optional, and a trailing slash
+/// matches everything in the directory.
+fn glob_matches(pattern: &str, path: &str) -> bool {
+    let pattern = pattern.strip_prefix('/').unwrap_or(pattern);
+    if pattern.ends_with('/') {
+        glob_matches_from(&format!("{}**", pattern), path)
+    } else {
+        glob_matches_from(pattern, path)
+    }
+}
+
+/// Match the rest of the pattern against the rest of the path, for [`glob_matches`].
+fn glob_matches_from(pattern: &str, path: &str) -> bool {
+    let split_points = |max: usize| (0..=max).filter(move |&i| path.is_char_boundary(i));
+    if let Some(rest) = pattern.strip_prefix("**") {
+        // A `**/` can also match no directory at all.
+        let no_dir = rest.strip_prefix('/').map_or(false, |r| glob_matches_from(r, path));
+        return no_dir || split_points(path.len()).any(|i| glob_matches_from(rest, &path[i..]));
+    }
+    if let Some(rest) = pattern.strip_prefix('*') {
+        // Try every split point up to the next slash, the star can't match one.
+        let star_max = path.find('/').unwrap_or(path.len());
+        return split_points(star_max).any(|i| glob_matches_from(rest, &path[i..]));
+    }
+    match (pattern.chars().next(), path.chars().next()) {
+        (None, None) => true,
+        (Some('?'), Some(c)) if c != '/' => {
+            glob_matches_from(&pattern[1..], &path[c.len_utf8()..])
+        }
+        (Some(p), Some(c)) if p == c => {
+            glob_matches_from(&pattern[p.len_utf8()..], &path[c.len_utf8()..])
+        }
+        _ => false,
+    }
+}
+
+/// Return whether the file name ends in `.ext` for one of the extensions.
+fn has_extension(name: &str, extensions: &[String]) -> bool {
+    match name.rsplit_once('.') {
+        Some((_, ext)) => extensions.iter().any(|e| e == ext),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_matches_exact_paths() {
+        assert!(glob_matches("about", "about"));
+        assert!(glob_matches("docs/about", "docs/about"));
+        assert!(!glob_matches("about", "docs/about"));
+        assert!(!glob_matches("docs/about", "about"));
+        assert!(!glob_matches("about", "about.html"));
+        assert!(!glob_matches("about", "abou"));
+        assert!(!glob_matches("", "about"));
+    }
+
+    #[test]
+    fn glob_matches_star_within_a_name() {
+        assert!(glob_matches("blog/*", "blog/post"));
+        assert!(glob_matches("blog/*.html", "blog/post.html"));
+        assert!(glob_matches("*.html", "index.html"));
+        assert!(glob_matches("*/index.html", "docs/index.html"));
+        assert!(glob_matches("a*b*c", "aXbYc"));
+        assert!(!glob_matches("blog/*", "blog/2022/post"));
+        assert!(!glob_matches("*.html", "docs/index.html"));
+        assert!(!glob_matches("blog/*.html", "blog/post.htm"));
+        assert!(!glob_matches("*/index.html", "index.html"));
+    }
+
+    #[test]
+    fn glob_matches_question_mark_as_one_character() {
+        assert!(glob_matches("page?.html", "page1.html"));
+        assert!(glob_matches("p?ge", "päge"));
+        assert!(!glob_matches("page?.html", "page.html"));
+        assert!(!glob_matches("page?.html", "page12.html"));
+        assert!(!glob_matches("blog?post", "blog/post"));
+    }
+
+    #[test]
+    fn glob_matches_double_star_across_directories() {
+        assert!(glob_matches("blog/**", "blog/post"));
+        assert!(glob_matches("blog/**", "blog/2022/10/post"));
+        assert!(glob_matches("**/index.html", "index.html"));
+        assert!(glob_matches("**/index.html", "docs/api/index.html"));
+        assert!(glob_matches("docs/**/*.html", "docs/a.html"));
+        assert!(glob_matches("docs/**/*.html", "docs/api/v1/a.html"));
+        assert!(!glob_matches("blog/**", "blogs/post"));
+        assert!(!glob_matches("**/index.html", "docs/index.htm"));
+        assert!(!glob_matches("docs/**/*.html", "api/a.html"));
+    }
+
+    #[test]
+    fn glob_matches_leading_and_trailing_slashes() {
+        assert!(glob_matches("/about", "about"));
+        assert!(glob_matches("/blog/*", "blog/post"));
+        assert!(glob_matches("blog/", "blog/post"));
+        assert!(glob_matches("blog/", "blog/2022/post"));
+        assert!(glob_matches("/blog/", "blog/post"));
+        assert!(!glob_matches("blog/", "blog"));
+        assert!(!glob_matches("blog/", "blogs/post"));
+        assert!(!glob_matches("/blog/", "docs/blog/post"));
+    }
+
+    #[test]
+    fn has_extension_looks_at_the_last_extension() {
+        let extensions = vec!["html".to_string(), "gz".to_string()];
+        assert!(has_extension("index.html", &extensions));
+        assert!(has_extension("archive.tar.gz", &extensions));
+        assert!(has_extension("page.min.html", &extensions));
+        assert!(!has_extension("index.html.bak", &extensions));
+        assert!(!has_extension("html", &extensions));
+        assert!(!has_extension("about", &extensions));
+        assert!(!has_extension("index.htm", &extensions));
+        assert!(!has_extension("index.html", &[]));
+    }
+}
@@ -0,0 +1,245 @@
+// Minimizer -- Site minifier for MkDocs sites that use the Kilsbergen theme
+// Copyright 2022 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! A minimal html tokenizer, for finding and rewriting tags.
+//!
+//! This is not a full html parser, it does not build a tree and it does not
+//! decode entities. It does understand enough of html to only report actual
+//! tags: it skips comments, doctypes, and the raw text content of elements such
+//! as `<script>` and `<style>`, so markup that occurs in a code sample does not
+//! get mistaken for a tag.
+
+use std::ops::Range;
+
+/// An attribute of a tag.
+#[derive(Debug, Clone)]
+pub struct Attribute<'a> {
+    /// The attribute name, as written in the document.
+    pub name: &'a str,
+
+    /// The attribute value without quotes, or `None` if it had no value.
+    pub value: Option<&'a str>,
+
+    /// Byte range of the full attribute, including the value and quotes.
+    pub span: Range<usize>,
+}
+
+/// A start or end tag in an html document.
+#[derive(Debug, Clone)]
+pub struct Tag<'a> {
+    /// The tag name, as written in the document.
+    pub name: &'a str,
+
+    /// Whether this is an end tag, like `</p>`.
+    pub is_end: bool,
+
+    /// Whether the tag ends in `/>`.
+    pub is_self_closing: bool,
+
+    /// The attributes in the order in which they occur.
+    pub attributes: Vec<Attribute<'a>>,
+
+    /// Byte range of the tag, from `<` up to and including `>`.
+    pub span: Range<usize>,
+}
+
+impl<'a> Tag<'a> {
+    /// Return whether this tag has the given name, ignoring case.
+    pub fn is(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+    }
+
+    /// Return the first attribute with the given name, ignoring case.
+    pub fn attribute(&self, name: &str) -> Option<&Attribute<'a>> {
+        self.attributes
+            .iter()
+            .find(|attr| attr.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Return the value of the attribute, `None` if absent or without value.
+    pub fn value(&self, name: &str) -> Option<&'a str> {
+        self.attribute(name).and_then(|attr| attr.value)
+    }
+}
+
+/// Elements whose content is raw text, in which `<` does not start a tag.
+const RAW_TEXT_ELEMENTS: [&str; 4] = ["script", "style", "textarea", "title"];
+
+/// Iterator over the tags in an html document.
+pub struct Tags<'a> {
+    html: &'a str,
+    pos: usize,
+}
+
+/// Return an iterator over the tags in the document, in document order.
+pub fn tags(html: &str) -> Tags {
+    Tags { html, pos: 0 }
+}
+
+/// Find `needle` in `haystack` at or after `from`, ignoring ASCII case.
+fn find_ignore_case(haystack: &str, needle: &str, from: usize) -> Option<usize> {
+    let hay = haystack.as_bytes();
+    let needle = needle.as_bytes();
+    (from..hay.len().saturating_sub(needle.len() - 1))
+        .find(|&i| hay[i..i + needle.len()].eq_ignore_ascii_case(needle))
+}
+
+impl<'a> Tags<'a> {
+    /// Parse a tag that starts at `start`, return `None` if it is not a tag.
+    fn parse_tag(&self, start: usize) -> Option<Tag<'a>> {
+        let bytes = self.html.as_bytes();
+        let mut i = start + 1;
+        let is_end = bytes.get(i) == Some(&b'/');
+        if is_end {
+            i += 1;
+        }
+
+        let name_start = i;
+        while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'-') {
+            i += 1;
+        }
+        if i == name_start || !bytes[name_start].is_ascii_alphabetic() {
+            return None;
+        }
+        let name = &self.html[name_start..i];
+
+        let mut attributes = Vec::new();
+        let mut is_self_closing = false;
+        loop {
+            while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            match bytes.get(i) {
+                None => return None,
+                Some(b'>') => break,
+                Some(b'/') if bytes.get(i + 1) == Some(&b'>') => {
+                    is_self_closing = true;
+                    i += 1;
+                    break;
+                }
+                Some(b'/') => {
+                    i += 1;
+                    continue;
+                }
+                Some(_) => {}
+            }
+
+            let attr_start = i;
+            while i < bytes.len()
+                && !bytes[i].is_ascii_whitespace()
+                && !matches!(bytes[i], b'=' | b'>')
+                && (bytes[i] != b'/' || bytes.get(i + 1) != Some(&b'>'))
+            {
+                i += 1;
+            }
+            let attr_name = &self.html[attr_start..i];
+
+            let mut j = i;
+            while j < bytes.len() && bytes[j].is_ascii_whitespace() {
+                j += 1;
+            }
+            let mut value = None;
+            if bytes.get(j) == Some(&b'=') {
+                j += 1;
+                while j < bytes.len() && bytes[j].is_ascii_whitespace() {
+                    j += 1;
+                }
+                match bytes.get(j) {
+                    Some(&quote) if quote == b'"' || quote == b'\'' => {
+                        let value_start = j + 1;
+                        let len = self.html[value_start..].find(quote as char)?;
+                        value = Some(&self.html[value_start..value_start + len]);
+                        j = value_start + len + 1;
+                    }
+                    _ => {
+                        let value_start = j;
+                        while j < bytes.len() && !bytes[j].is_ascii_whitespace() && bytes[j] != b'>' {
+                            j += 1;
+                        }
+                        value = Some(&self.html[value_start..j]);
+                    }
+                }
+                i = j;
+            }
+
+            attributes.push(Attribute {
+                name: attr_name,
+                value,
+                span: attr_start..i,
+            });
+        }
+
+        Some(Tag {
+            name,
+            is_end,
+            is_self_closing,
+            attributes,
+            span: start..i + 1,
+        })
+    }
+}
+
+impl<'a> Iterator for Tags<'a> {
+    type Item = Tag<'a>;
+
+    fn next(&mut self) -> Option<Tag<'a>> {
+        loop {
+            let start = self.pos + self.html[self.pos..].find('<')?;
+            let rest = &self.html[start..];
+
+            if rest.starts_with("<!--") {
+                self.pos = match rest.find("-->") {
+                    Some(n) => start + n + 3,
+                    None => self.html.len(),
+                };
+                continue;
+            }
+            if rest.starts_with("<!") || rest.starts_with("<?") {
+                self.pos = match rest.find('>') {
+                    Some(n) => start + n + 1,
+                    None => self.html.len(),
+                };
+                continue;
+            }
+
+            let tag = match self.parse_tag(start) {
+                Some(tag) => tag,
+                None => {
+                    self.pos = start + 1;
+                    continue;
+                }
+            };
+            self.pos = tag.span.end;
+
+            // Skip over the content of raw text elements, up to the end tag.
+            let is_raw = RAW_TEXT_ELEMENTS.iter().any(|name| tag.is(name));
+            if !tag.is_end && !tag.is_self_closing && is_raw {
+                let end_tag = format!("</{}", tag.name);
+                self.pos = find_ignore_case(self.html, &end_tag, self.pos).unwrap_or(self.html.len());
+            }
+
+            return Some(tag);
+        }
+    }
+}
+
+/// Apply replacements of byte ranges to a document.
+///
+/// The ranges must not overlap, they are applied in order of their start.
+pub fn splice(html: &str, mut replacements: Vec<(Range<usize>, String)>) -> String {
+    replacements.sort_by_key(|(range, _)| range.start);
+    let mut result = String::with_capacity(html.len());
+    let mut pos = 0;
+    for (range, replacement) in replacements {
+        assert!(range.start >= pos, "Replacements must not overlap.");
+        result.push_str(&html[pos..range.start]);
+        result.push_str(&replacement);
+        pos = range.end;
+    }
+    result.push_str(&html[pos..]);
+    result
+}
@@ -0,0 +1,295 @@
+// Minimizer -- Site minifier for MkDocs sites that use the Kilsbergen theme
+// Copyright 2022 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Inlining of small stylesheets and scripts into the pages that link them.
+
+use git2::{ObjectType, Repository, Tree};
+use std::path::Path;
+
+use minimizer::html;
+
+type Result<T> = std::result::Result<T, git2::Error>;
+
+/// Resolve a reference in a page to a path relative to the root of the tree.
+///
+/// `page_dir` is the directory that contains the page, relative to the root,
+/// with a trailing slash unless it is the root itself. Returns `None` for
+/// references that do not point into the tree, such as external urls.
+fn resolve(page_dir: &str, reference: &str) -> Option<String> {
+    if reference.contains("://") || reference.starts_with("//") || reference.starts_with("data:") {
+        return None;
+    }
+
+    // The query string and fragment are not part of the file name.
+    let reference = reference.split(|c| c == '?' || c == '#').next()?;
+
+    let full = match reference.strip_prefix('/') {
+        Some(absolute) => absolute.to_string(),
+        None => format!("{}{}", page_dir, reference),
+    };
+
+    let mut parts: Vec<&str> = Vec::new();
+    for part in full.split('/') {
+        match part {
+            "" | "." => continue,
+            // A reference that escapes the root does not point into the tree.
+            ".." => {
+                parts.pop()?;
+            }
+            _ => parts.push(part),
+        }
+    }
+
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join("/"))
+    }
+}
+
+/// Return the contents of a file in the tree, if it is at most `max_bytes`.
+///
+/// Returns `None` if the file does not exist, is too large, or is not UTF-8.
+fn read_small_file(
+    repo: &Repository,
+    root: &Tree,
+    path: &str,
+    max_bytes: usize,
+) -> Result<Option<String>> {
+    let entry = match root.get_path(Path::new(path)) {
+        Ok(entry) => entry,
+        Err(err) if err.code() == git2::ErrorCode::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if entry.kind() != Some(ObjectType::Blob) {
+        return Ok(None);
+    }
+    let blob = repo.find_blob(entry.id())?;
+    if blob.size() > max_bytes {
+        return Ok(None);
+    }
+    Ok(std::str::from_utf8(blob.content()).ok().map(|s| s.to_string()))
+}
+
+/// Replace references to small local stylesheets and scripts with their content.
+///
+/// Stylesheets linked with `<link rel=stylesheet>` become a `<style>` element,
+/// and `<script src>` elements get the script as content. Files larger than
+/// `max_bytes` are left alone, and so are stylesheets that contain `url()`,
+/// because relative urls in them would resolve differently once inlined, and
+/// scripts with `async`, `defer`, or `type=module`, whose execution order would
+/// change. Returns `None` if there was nothing to inline.
+pub fn inline_assets(
+    repo: &Repository,
+    root: &Tree,
+    page_dir: &str,
+    page: &str,
+    max_bytes: usize,
+) -> Result<Option<String>> {
+    let tags: Vec<html::Tag> = html::tags(page).collect();
+    let mut replacements = Vec::new();
+
+    for (i, tag) in tags.iter().enumerate() {
+        if tag.is_end {
+            continue;
+        }
+
+        let is_stylesheet = tag.is("link")
+            && tag
+                .value("rel")
+                .map_or(false, |rel| rel.eq_ignore_ascii_case("stylesheet"));
+
+        if is_stylesheet {
+            let path = match tag.value("href").and_then(|href| resolve(page_dir, href)) {
+                Some(path) => path,
+                None => continue,
+            };
+            let css = match read_small_file(repo, root, &path, max_bytes)? {
+                Some(css) => css,
+                None => continue,
+            };
+            // Relative urls and imports would resolve against the page rather
+            // than the stylesheet once inline.
+            let css_lower = css.to_ascii_lowercase();
+            if ["url(", "@import", "</style"].iter().any(|s| css_lower.contains(s)) {
+                continue;
+            }
+            // The value may have been single-quoted, we always double-quote it.
+            let style = match tag.value("media") {
+                Some(media) => {
+                    let media = media.replace('"', "&quot;");
+                    format!("<style media=\"{}\">{}</style>", media, css)
+                }
+                None => format!("<style>{}</style>", css),
+            };
+            replacements.push((tag.span.clone(), style));
+        }
+
+        if tag.is("script") && !tag.is_self_closing {
+            let src = match tag.value("src") {
+                Some(src) => src,
+                None => continue,
+            };
+            // The inline script is a bare `<script>`, so any other attribute,
+            // such as `async`, `nomodule`, `nonce`, or a non-JavaScript `type`,
+            // would be lost. Leave those scripts alone.
+            let is_plain = tag.attributes.iter().all(|attr| match attr.name {
+                name if name.eq_ignore_ascii_case("src") => true,
+                name if name.eq_ignore_ascii_case("type") => attr
+                    .value
+                    .map_or(false, |t| t.eq_ignore_ascii_case("text/javascript")),
+                _ => false,
+            });
+            if !is_plain {
+                continue;
+            }
+            // The tokenizer skips the content of the script, so the next tag
+            // is the end tag. There should be no content in between.
+            let end = match tags.get(i + 1) {
+                Some(end) if end.is("script") && end.is_end => end,
+                _ => continue,
+            };
+            if !page[tag.span.end..end.span.start].trim().is_empty() {
+                continue;
+            }
+            let path = match resolve(page_dir, src) {
+                Some(path) => path,
+                None => continue,
+            };
+            let js = match read_small_file(repo, root, &path, max_bytes)? {
+                Some(js) => js,
+                None => continue,
+            };
+            if js.to_ascii_lowercase().contains("</script") {
+                continue;
+            }
+            replacements.push((tag.span.start..end.span.end, format!("<script>{}</script>", js)));
+        }
+    }
+
+    if replacements.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(html::splice(page, replacements)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_handles_relative_and_absolute_references() {
+        assert_eq!(resolve("", "style.css").as_deref(), Some("style.css"));
+        assert_eq!(resolve("docs/", "style.css").as_deref(), Some("docs/style.css"));
+        assert_eq!(resolve("docs/", "./style.css").as_deref(), Some("docs/style.css"));
+        assert_eq!(resolve("docs/api/", "../style.css").as_deref(), Some("docs/style.css"));
+        assert_eq!(resolve("docs/", "/style.css").as_deref(), Some("style.css"));
+        assert_eq!(resolve("docs/", "/css//style.css").as_deref(), Some("css/style.css"));
+    }
+
+    #[test]
+    fn resolve_strips_query_and_fragment() {
+        assert_eq!(resolve("", "style.css?v=2").as_deref(), Some("style.css"));
+        assert_eq!(resolve("", "app.js#main").as_deref(), Some("app.js"));
+        assert_eq!(resolve("", "app.js?v=2#main").as_deref(), Some("app.js"));
+        assert_eq!(resolve("", "?v=2"), None);
+    }
+
+    #[test]
+    fn resolve_rejects_references_outside_the_tree() {
+        assert_eq!(resolve("", "../style.css"), None);
+        assert_eq!(resolve("docs/", "../../style.css"), None);
+        assert_eq!(resolve("docs/", "/../style.css"), None);
+        assert_eq!(resolve("", "https://example.com/style.css"), None);
+        assert_eq!(resolve("", "//example.com/style.css"), None);
+        assert_eq!(resolve("", "data:text/css,p{}"), None);
+        assert_eq!(resolve("docs/", "/"), None);
+    }
+
+    /// Create a repository with the files at the root of a tree, and call `f` with it.
+    fn with_tree<F: FnOnce(&Repository, &Tree)>(name: &str, files: &[(&str, &str)], f: F) {
+        let dir = std::env::temp_dir()
+            .join(format!("minimizer-inline-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        let repo = Repository::init_bare(&dir).unwrap();
+        let mut builder = repo.treebuilder(None).unwrap();
+        for (name, content) in files {
+            let blob = repo.blob(content.as_bytes()).unwrap();
+            builder.insert(name, blob, 0o100644).unwrap();
+        }
+        let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+        f(&repo, &tree);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Inline the assets of `page` at the root, with the files in the tree.
+    fn inline(name: &str, files: &[(&str, &str)], page: &str) -> Option<String> {
+        let mut result = None;
+        with_tree(name, files, |repo, tree| {
+            result = inline_assets(repo, tree, "", page, 1000).unwrap();
+        });
+        result
+    }
+
+    #[test]
+    fn inline_assets_inlines_stylesheets_and_scripts() {
+        let files = [("a.css", "p{color:red}"), ("a.js", "go()")];
+        let page = "<link rel=stylesheet href=a.css><script src=a.js></script>";
+        assert_eq!(
+            inline("plain", &files, page).as_deref(),
+            Some("<style>p{color:red}</style><script>go()</script>"),
+        );
+        let page = "<script type=text/javascript src=a.js></script>";
+        assert_eq!(inline("type", &files, page).as_deref(), Some("<script>go()</script>"));
+    }
+
+    #[test]
+    fn inline_assets_escapes_quotes_in_media() {
+        let files = [("a.css", "p{}")];
+        let page = r#"<link rel=stylesheet href=a.css media='print and (x="y")'>"#;
+        assert_eq!(
+            inline("media", &files, page).as_deref(),
+            Some(r#"<style media="print and (x=&quot;y&quot;)">p{}</style>"#),
+        );
+    }
+
+    #[test]
+    fn inline_assets_skips_css_that_depends_on_its_location() {
+        let sheets = ["p{background:URL(bg.png)}", "@import 'b.css';", "</STYLE>"];
+        for (i, css) in sheets.iter().enumerate() {
+            let files = [("a.css", *css)];
+            let page = "<link rel=stylesheet href=a.css>";
+            assert_eq!(inline(&format!("css-{}", i), &files, page), None, "{}", css);
+        }
+    }
+
+    #[test]
+    fn inline_assets_skips_scripts_that_are_not_plain() {
+        let files = [("a.js", "go()"), ("end.js", "x='</Script>'")];
+        let pages = [
+            "<script src=end.js></script>",
+            "<script src=a.js async></script>",
+            "<script src=a.js defer></script>",
+            "<script src=a.js type=module></script>",
+            "<script src=a.js nonce=abc></script>",
+            "<script src=a.js>go()</script>",
+            "<script src=missing.js></script>",
+            "<script src=https://example.com/a.js></script>",
+        ];
+        for (i, page) in pages.iter().enumerate() {
+            assert_eq!(inline(&format!("js-{}", i), &files, page), None, "{}", page);
+        }
+    }
+
+    #[test]
+    fn inline_assets_skips_large_files() {
+        let css = "p{}".repeat(500);
+        let files = [("a.css", css.as_str())];
+        assert_eq!(inline("large", &files, "<link rel=stylesheet href=a.css>"), None);
+    }
+}
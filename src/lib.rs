@@ -0,0 +1,736 @@
+// Minimizer -- Site minifier for MkDocs sites that use the Kilsbergen theme
+// Copyright 2022 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! The minification and compression pipeline of Minimizer.
+//!
+//! These are the building blocks that the `minimizer` binary applies to every
+//! html file in the tree. They are exposed so they can be used on individual
+//! documents, for example to compare against other minifiers. Walking a tree,
+//! with the cache and progress reporting, is part of the binary, not of the
+//! library.
+
+pub mod html;
+
+/// Sizes, in bytes, of an html document in various forms.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Sizes {
+    /// Size of the document as it was in the source tree.
+    pub original_len: usize,
+
+    /// Size of the minified document.
+    pub minified_len: usize,
+
+    /// Size of the minified document after gzip compression.
+    pub gz_len: usize,
+
+    /// Size of the minified document after Brotli compression.
+    pub br_len: usize,
+
+    /// Size of the minified document after raw Deflate compression, or zero if
+    /// no Deflate version was produced.
+    pub deflate_len: usize,
+}
+
+impl Sizes {
+    /// Return the minified size as a percentage of the original size.
+    pub fn minified_pct(&self) -> f32 {
+        100.0 * self.minified_len as f32 / self.original_len as f32
+    }
+
+    /// Return the gzipped size as a percentage of the original size.
+    pub fn gz_pct(&self) -> f32 {
+        100.0 * self.gz_len as f32 / self.original_len as f32
+    }
+
+    /// Return the Brotli-compressed size as a percentage of the original size.
+    pub fn br_pct(&self) -> f32 {
+        100.0 * self.br_len as f32 / self.original_len as f32
+    }
+
+    /// Return the Deflate-compressed size as a percentage of the original size.
+    pub fn deflate_pct(&self) -> f32 {
+        100.0 * self.deflate_len as f32 / self.original_len as f32
+    }
+}
+
+impl std::fmt::Display for Sizes {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Original: {}, Minified: {} ({:.1}%), Gzip: {} ({:.1}%), Brotli: {} ({:.1}%)",
+            self.original_len,
+            self.minified_len,
+            self.minified_pct(),
+            self.gz_len,
+            self.gz_pct(),
+            self.br_len,
+            self.br_pct(),
+        )?;
+        if self.deflate_len > 0 {
+            write!(f, ", Deflate: {} ({:.1}%)", self.deflate_len, self.deflate_pct())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::ops::Add for Sizes {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        Self {
+            original_len: self.original_len + other.original_len,
+            minified_len: self.minified_len + other.minified_len,
+            gz_len: self.gz_len + other.gz_len,
+            br_len: self.br_len + other.br_len,
+            deflate_len: self.deflate_len + other.deflate_len,
+        }
+    }
+}
+
+/// The number of Zopfli iterations to use when not scaling by input size.
+///
+/// Be slow but compress well, only really feasible for small files, but my
+/// html files are small, so that's fine.
+pub const ZOPFLI_ITERATIONS_DEFAULT: u8 = 20;
+
+/// Return the number of Zopfli iterations to use for an input of a given size.
+///
+/// For small files, additional iterations are cheap, so we can afford to spend
+/// more effort there. For large files, every iteration is expensive, and the
+/// gains of additional iterations are small.
+pub fn zopfli_iterations_adaptive(input_len: usize) -> u8 {
+    match input_len {
+        n if n < 16 * 1024 => 50,
+        n if n < 64 * 1024 => ZOPFLI_ITERATIONS_DEFAULT,
+        n if n < 256 * 1024 => 10,
+        _ => 5,
+    }
+}
+
+/// Gzip-compress the input using Zopfli at high compression (slow to run).
+pub fn compress_zopfli(input: &[u8], iterations: u8) -> Vec<u8> {
+    compress_zopfli_format(input, iterations, zopfli::Format::Gzip)
+}
+
+/// Compress the input to raw Deflate, without gzip or zlib header, using Zopfli.
+///
+/// This is what some clients expect for `Content-Encoding: deflate`.
+pub fn compress_deflate(input: &[u8], iterations: u8) -> Vec<u8> {
+    compress_zopfli_format(input, iterations, zopfli::Format::Deflate)
+}
+
+/// Gzip-compress the input with flate2, much faster than Zopfli, but larger.
+pub fn compress_gzip_fast(input: &[u8]) -> Vec<u8> {
+    use std::io::Write;
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(input).expect("No IO happens here, should not fail.");
+    encoder.finish().expect("No IO happens here, should not fail.")
+}
+
+/// Like [`compress_gzip_fast`], but to raw Deflate, see [`compress_deflate`].
+pub fn compress_deflate_fast(input: &[u8]) -> Vec<u8> {
+    use std::io::Write;
+    let level = flate2::Compression::best();
+    let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), level);
+    encoder.write_all(input).expect("No IO happens here, should not fail.");
+    encoder.finish().expect("No IO happens here, should not fail.")
+}
+
+/// Compress the input with Zopfli into the given container format.
+fn compress_zopfli_format(input: &[u8], iterations: u8, format: zopfli::Format) -> Vec<u8> {
+    let opts = zopfli::Options {
+        iteration_count: std::num::NonZeroU8::new(iterations)
+            .expect("Zopfli needs at least one iteration."),
+        // Not sure what this does, use the default value.
+        maximum_block_splits: 15,
+    };
+    let mut output = Vec::new();
+    let input = std::io::Cursor::new(input);
+    zopfli::compress(&opts, &format, input, &mut output)
+        .expect("Zopfli compression should not fail, we don't do IO here.");
+
+    output
+}
+
+/// The default Brotli compression level, the maximum.
+pub const BROTLI_LEVEL_DEFAULT: u32 = 11;
+
+/// What kind of input Brotli can tune its compression for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BrotliMode {
+    /// Nothing is known about the input.
+    Generic,
+    /// The input is UTF-8 text, such as html, CSS, and scripts.
+    Text,
+    /// The input is a font, as in WOFF 2.0.
+    Font,
+}
+
+impl BrotliMode {
+    /// Return the mode with the given name, as used on the command line.
+    pub fn from_name(name: &str) -> Option<BrotliMode> {
+        match name {
+            "generic" => Some(BrotliMode::Generic),
+            "text" => Some(BrotliMode::Text),
+            "font" => Some(BrotliMode::Font),
+            _ => None,
+        }
+    }
+}
+
+/// Brotli-compress the input at the given level, from 0 to 11.
+pub fn compress_brotli(input: &[u8], level: u32, mode: BrotliMode) -> Vec<u8> {
+    use brotli2::CompressMode;
+    use std::io::Write;
+    let mode = match mode {
+        BrotliMode::Generic => CompressMode::Generic,
+        BrotliMode::Text => CompressMode::Text,
+        BrotliMode::Font => CompressMode::Font,
+    };
+    let mut params = brotli2::CompressParams::new();
+    params.quality(level).mode(mode);
+    let mut encoder = brotli2::write::BrotliEncoder::from_params(Vec::new(), &params);
+    encoder
+        .write_all(input)
+        .expect("No IO happens here, should not fail.");
+    encoder
+        .finish()
+        .expect("No IO happens here, should not fail.")
+}
+
+/// A compression format that we produce a version of a page in.
+pub trait Compressor: Send + Sync {
+    /// The extension of the compressed file, without dot, such as `gz`.
+    fn extension(&self) -> &str;
+
+    /// Compress the input.
+    fn compress(&self, input: &[u8]) -> Vec<u8>;
+
+    /// Compress the input to the same format, quickly rather than well.
+    fn compress_fast(&self, input: &[u8]) -> Vec<u8>;
+}
+
+/// Gzip compression with Zopfli, see [`compress_zopfli`].
+pub struct Gzip {
+    pub iterations: u8,
+}
+
+impl Compressor for Gzip {
+    fn extension(&self) -> &str {
+        "gz"
+    }
+
+    fn compress(&self, input: &[u8]) -> Vec<u8> {
+        compress_zopfli(input, self.iterations)
+    }
+
+    fn compress_fast(&self, input: &[u8]) -> Vec<u8> {
+        compress_gzip_fast(input)
+    }
+}
+
+/// Raw Deflate compression with Zopfli, see [`compress_deflate`].
+pub struct Deflate {
+    pub iterations: u8,
+}
+
+impl Compressor for Deflate {
+    fn extension(&self) -> &str {
+        "deflate"
+    }
+
+    fn compress(&self, input: &[u8]) -> Vec<u8> {
+        compress_deflate(input, self.iterations)
+    }
+
+    fn compress_fast(&self, input: &[u8]) -> Vec<u8> {
+        compress_deflate_fast(input)
+    }
+}
+
+/// Brotli compression, see [`compress_brotli`].
+pub struct Brotli {
+    pub level: u32,
+    pub mode: BrotliMode,
+}
+
+impl Compressor for Brotli {
+    fn extension(&self) -> &str {
+        "br"
+    }
+
+    fn compress(&self, input: &[u8]) -> Vec<u8> {
+        compress_brotli(input, self.level, self.mode)
+    }
+
+    fn compress_fast(&self, input: &[u8]) -> Vec<u8> {
+        compress_brotli(input, self.level.min(5), self.mode)
+    }
+}
+
+/// Decompress gzip data, such as produced by [`compress_zopfli`].
+pub fn decompress_gzip(input: &[u8]) -> std::io::Result<Vec<u8>> {
+    use std::io::Read;
+    let mut output = Vec::new();
+    flate2::read::GzDecoder::new(input).read_to_end(&mut output)?;
+    Ok(output)
+}
+
+/// Decompress Brotli data, such as produced by [`compress_brotli`].
+pub fn decompress_brotli(input: &[u8]) -> std::io::Result<Vec<u8>> {
+    use std::io::Read;
+    let mut output = Vec::new();
+    brotli2::read::BrotliDecoder::new(input).read_to_end(&mut output)?;
+    Ok(output)
+}
+
+/// The license notice for the Kilsbergen theme and the fonts that it uses.
+pub const DEFAULT_BANNER: &str = "\
+    Kilsbergen MkDocs theme copyright 2022 Ruud van Asseldonk,\n\
+    licensed Apache 2.0, https://github.com/ruuda/kilsbergen.\n\
+    Inter font family copyright Rasmus Andersson,\n\
+    licensed SIL OFL 1.1, https://rsms.me/inter/.";
+
+/// Remove the whitespace between the tokens of a JSON document.
+///
+/// This does not validate the document, it only tracks strings, so whitespace
+/// inside strings is preserved.
+pub fn minify_json(json: &str) -> String {
+    let mut result = String::with_capacity(json.len());
+    let mut in_string = false;
+    let mut is_escaped = false;
+
+    for ch in json.chars() {
+        if in_string {
+            result.push(ch);
+            match ch {
+                _ if is_escaped => is_escaped = false,
+                '\\' => is_escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+        } else if !ch.is_ascii_whitespace() {
+            in_string = ch == '"';
+            result.push(ch);
+        }
+    }
+
+    result
+}
+
+/// Minify the content of `<script type="application/ld+json">` elements.
+///
+/// Other scripts, including `application/json` data blocks, are left alone.
+/// Returns `None` if the document has no JSON-LD.
+fn minify_json_ld(input: &str) -> Option<String> {
+    let tags: Vec<html::Tag> = html::tags(input).collect();
+    let mut replacements = Vec::new();
+
+    for (tag, end) in tags.iter().zip(tags.iter().skip(1)) {
+        let is_json_ld = tag.is("script")
+            && !tag.is_end
+            && tag
+                .value("type")
+                .map_or(false, |t| t.trim().eq_ignore_ascii_case("application/ld+json"));
+        // The tokenizer skips the content of the script, so the next tag is
+        // the end tag.
+        if is_json_ld && end.is("script") && end.is_end {
+            let content = tag.span.end..end.span.start;
+            replacements.push((content.clone(), minify_json(&input[content])));
+        }
+    }
+
+    if replacements.is_empty() {
+        None
+    } else {
+        Some(html::splice(input, replacements))
+    }
+}
+
+/// Elements in which whitespace is significant, or that contain raw text.
+const PRESERVE_WHITESPACE_ELEMENTS: [&str; 4] = ["pre", "textarea", "script", "style"];
+
+/// Minify XHTML, by collapsing whitespace only.
+///
+/// Minify-html has no XML mode, and its html mode drops the slash of
+/// self-closing tags and the quotes around attribute values, which breaks
+/// well-formedness. Here we only collapse runs of whitespace in text to a single
+/// space, outside of `<pre>` and similar elements. Comments, CDATA sections,
+/// and processing instructions are left alone. The banner is inserted as a
+/// comment after the `<html>` start tag.
+pub fn minify_xhtml(input: &[u8], banner: &str) -> Vec<u8> {
+    let doc = match std::str::from_utf8(input) {
+        Ok(doc) => doc,
+        Err(_) => return input.to_vec(),
+    };
+
+    let mut replacements = Vec::new();
+    let mut preserve_until = None;
+    let mut text_start = 0;
+
+    for tag in html::tags(doc) {
+        let text = &doc[text_start..tag.span.start];
+        // Text that contains a '<' has a comment or similar in it, which the
+        // tokenizer skipped.
+        if preserve_until.is_none() && !text.contains('<') {
+            let mut collapsed = String::with_capacity(text.len());
+            let mut in_whitespace = false;
+            for ch in text.chars() {
+                if !ch.is_ascii_whitespace() {
+                    collapsed.push(ch);
+                } else if !in_whitespace {
+                    collapsed.push(' ');
+                }
+                in_whitespace = ch.is_ascii_whitespace();
+            }
+            if collapsed != text {
+                replacements.push((text_start..tag.span.start, collapsed));
+            }
+        }
+        text_start = tag.span.end;
+
+        match preserve_until {
+            Some(name) if tag.is_end && tag.is(name) => preserve_until = None,
+            Some(_) => {}
+            None if tag.is_end || tag.is_self_closing => {}
+            None => {
+                preserve_until = PRESERVE_WHITESPACE_ELEMENTS
+                    .iter()
+                    .copied()
+                    .find(|name| tag.is(name));
+                if tag.is("html") && !banner.trim().is_empty() {
+                    let comment = format!("<!--\n{}\n-->", banner.trim_end());
+                    replacements.push((tag.span.end..tag.span.end, comment));
+                }
+            }
+        }
+    }
+
+    html::splice(doc, replacements).into_bytes()
+}
+
+/// Minify XML, by removing whitespace-only text between elements.
+///
+/// This is what pretty-printing adds, for example in sitemaps. Text that is not
+/// only whitespace is kept as-is, as are comments, CDATA sections, and
+/// processing instructions. Returns `None` if the input is not UTF-8, or if
+/// markup in it is not terminated.
+pub fn minify_xml(input: &[u8]) -> Option<Vec<u8>> {
+    let doc = std::str::from_utf8(input).ok()?;
+    let mut result = String::with_capacity(doc.len());
+    let mut pos = 0;
+
+    while let Some(n) = doc[pos..].find('<') {
+        let text = &doc[pos..pos + n];
+        if !text.trim().is_empty() {
+            result.push_str(text);
+        }
+
+        let start = pos + n;
+        let rest = &doc[start..];
+        let len = if rest.starts_with("<!--") {
+            rest.find("-->")? + 3
+        } else if rest.starts_with("<![CDATA[") {
+            rest.find("]]>")? + 3
+        } else if rest.starts_with("<?") {
+            rest.find("?>")? + 2
+        } else {
+            // A tag, where a '>' in a quoted attribute value does not end it.
+            let mut quote = None;
+            let end = rest.char_indices().skip(1).find(|&(_, ch)| match quote {
+                Some(q) if ch == q => {
+                    quote = None;
+                    false
+                }
+                Some(_) => false,
+                None if ch == '"' || ch == '\'' => {
+                    quote = Some(ch);
+                    false
+                }
+                None => ch == '>',
+            });
+            end?.0 + 1
+        };
+        result.push_str(&rest[..len]);
+        pos = start + len;
+    }
+
+    let text = &doc[pos..];
+    if !text.trim().is_empty() {
+        result.push_str(text);
+    }
+
+    Some(result.into_bytes())
+}
+
+/// Remove `<meta name>` elements with one of the given names, ignoring case.
+///
+/// Returns `None` if there were no such elements.
+pub fn strip_meta(html: &str, names: &[String]) -> Option<String> {
+    let replacements: Vec<_> = html::tags(html)
+        .filter(|tag| tag.is("meta") && !tag.is_end)
+        .filter(|tag| {
+            tag.value("name")
+                .map_or(false, |name| names.iter().any(|n| n.eq_ignore_ascii_case(name)))
+        })
+        .map(|tag| (tag.span, String::new()))
+        .collect();
+
+    if replacements.is_empty() {
+        None
+    } else {
+        Some(html::splice(html, replacements))
+    }
+}
+
+/// What to do with the line break at the end of a minified document.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TrailingNewline {
+    /// Leave the end of the document as the minifier produced it.
+    Keep,
+
+    /// End the document in exactly one newline.
+    Ensure,
+
+    /// Remove all line breaks at the end of the document.
+    Strip,
+}
+
+/// Convert CRLF and lone CR line endings to LF, and fix up the end.
+///
+/// Browsers and XML parsers normalize line endings in the same way, so this
+/// does not change how the document is interpreted.
+pub fn normalize_newlines(input: &[u8], trailing: TrailingNewline) -> Vec<u8> {
+    let mut result = Vec::with_capacity(input.len() + 1);
+    let mut bytes = input.iter().peekable();
+    while let Some(&b) = bytes.next() {
+        if b == b'\r' {
+            bytes.next_if_eq(&&b'\n');
+            result.push(b'\n');
+        } else {
+            result.push(b);
+        }
+    }
+
+    if trailing != TrailingNewline::Keep {
+        while result.last() == Some(&b'\n') {
+            result.pop();
+        }
+    }
+    if trailing == TrailingNewline::Ensure {
+        result.push(b'\n');
+    }
+
+    result
+}
+
+/// Count how often every start and end tag occurs, by lowercase name.
+fn count_tags(html: &str) -> std::collections::BTreeMap<String, usize> {
+    let mut counts = std::collections::BTreeMap::new();
+    for tag in html::tags(html) {
+        let name = format!("<{}{}>", if tag.is_end { "/" } else { "" }, tag.name);
+        *counts.entry(name.to_ascii_lowercase()).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// Return the values of the `id` attributes, in document order.
+fn ids(html: &str) -> Vec<&str> {
+    html::tags(html).filter_map(|tag| tag.value("id")).collect()
+}
+
+/// A check that minification preserved the structure of a page.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SanityCheck {
+    /// Every tag occurs as often in the minified page as in the original.
+    Tags,
+
+    /// The `id` attributes are the same and in the same order, so links to
+    /// anchors in the page still work.
+    Ids,
+}
+
+impl SanityCheck {
+    /// Return the check with the given name, as used on the command line.
+    pub fn from_name(name: &str) -> Option<SanityCheck> {
+        match name {
+            "tags" => Some(SanityCheck::Tags),
+            "ids" => Some(SanityCheck::Ids),
+            _ => None,
+        }
+    }
+
+    /// Compare the minified page against the original, describe any difference.
+    pub fn check(&self, original: &str, minified: &str) -> Result<(), String> {
+        match self {
+            SanityCheck::Tags => {
+                let before = count_tags(original);
+                let after = count_tags(minified);
+                for name in before.keys().chain(after.keys()) {
+                    let n_before = before.get(name).copied().unwrap_or(0);
+                    let n_after = after.get(name).copied().unwrap_or(0);
+                    if n_before != n_after {
+                        return Err(format!(
+                            "{} occurs {} times before minification, {} times after",
+                            name, n_before, n_after,
+                        ));
+                    }
+                }
+                Ok(())
+            }
+            SanityCheck::Ids => {
+                let before = ids(original);
+                let after = ids(minified);
+                if before == after {
+                    return Ok(());
+                }
+                let i = before.iter().zip(&after).take_while(|(x, y)| x == y).count();
+                Err(format!(
+                    "id {:?} became {:?} after minification",
+                    before.get(i).copied().unwrap_or("(none)"),
+                    after.get(i).copied().unwrap_or("(none)"),
+                ))
+            }
+        }
+    }
+}
+
+/// Minify html and embedded CSS and JSON-LD.
+///
+/// Minification strips comments, including the license notices. To preserve
+/// those, the banner is inserted as a comment at the start of the `<html>`
+/// element. An empty banner, or a document without `<html>`, gets no comment.
+pub fn minify_html(input: &[u8], banner: &str) -> Vec<u8> {
+    minify_html_cfg(input, banner, false, false)
+}
+
+/// Like [`minify_html`], but also minify inline JavaScript.
+pub fn minify_html_js(input: &[u8], banner: &str) -> Vec<u8> {
+    minify_html_cfg(input, banner, true, false)
+}
+
+/// Minify html, but keep the comments, and leave CSS and JSON-LD as they are.
+///
+/// This is for a staging deploy, where the output should remain readable.
+/// The license notices are comments, so they stay too, and there is no banner.
+pub fn minify_html_readable(input: &[u8]) -> Vec<u8> {
+    minify_html_cfg(input, "", false, true)
+}
+
+/// Return the number of bytes of inline JavaScript in an html document.
+///
+/// This counts the content of `<script>` elements that have no `src` and
+/// that are not of a data type such as JSON.
+pub fn inline_js_len(html: &str) -> usize {
+    let mut len = 0;
+    let mut tags = html::tags(html);
+    while let Some(tag) = tags.next() {
+        if !tag.is("script") || tag.is_end || tag.attribute("src").is_some() {
+            continue;
+        }
+        let is_js = match tag.value("type") {
+            None => true,
+            Some(t) => ["module", "text/javascript", "application/javascript"]
+                .iter()
+                .any(|js| t.eq_ignore_ascii_case(js)),
+        };
+        // The tokenizer skips the content, so the next tag is the end tag.
+        if let (true, Some(end)) = (is_js, tags.next()) {
+            len += end.span.start - tag.span.end;
+        }
+    }
+    len
+}
+
+/// Minify html, see [`minify_html`], and inline JavaScript if `minify_js`.
+///
+/// If `readable`, only collapse whitespace, see [`minify_html_readable`].
+fn minify_html_cfg(input: &[u8], banner: &str, minify_js: bool, readable: bool) -> Vec<u8> {
+    use std::str;
+
+    let cfg = minify_html::Cfg {
+        do_not_minify_doctype: true,
+        ensure_spec_compliant_unquoted_attribute_values: true,
+        keep_closing_tags: true,
+        keep_html_and_head_opening_tags: true,
+        keep_spaces_between_attributes: true,
+        keep_comments: readable,
+        minify_css: !readable,
+        minify_js,
+        remove_bangs: false,
+        remove_processing_instructions: true,
+    };
+
+    // Minify-html does not touch scripts when we don't minify js, so we do
+    // the structured data ourselves.
+    let json_ld_minified = match readable {
+        true => None,
+        false => str::from_utf8(input).ok().and_then(minify_json_ld),
+    };
+    let input = json_ld_minified.as_ref().map_or(input, |html| html.as_bytes());
+
+    let minified_bytes = minify_html::minify(input, &cfg);
+
+    if banner.trim().is_empty() {
+        return minified_bytes;
+    }
+
+    let minified_str = str::from_utf8(&minified_bytes[..])
+        .expect("File should be valid UTF-8.");
+
+    // Put back the copyright notices that minification would strip, as the
+    // first child of the html element, whatever attributes it has.
+    match html::tags(minified_str).find(|tag| tag.is("html") && !tag.is_end) {
+        Some(tag) => {
+            let comment = format!("<!--\n{}\n-->", banner.trim_end());
+            html::splice(minified_str, vec![(tag.span.end..tag.span.end, comment)]).into_bytes()
+        }
+        None => minified_bytes,
+    }
+}
+
+/// What the content of a file is, going by its first bytes rather than its name.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Sniffed {
+    /// An html document, starting with a doctype or `<html>` tag.
+    Html,
+
+    /// A png, jpeg, gif, webp, or ico image.
+    Image,
+
+    /// Anything else that contains a zero byte, so it is not text.
+    Binary,
+}
+
+/// Tell from the content whether a file is html, an image, or binary.
+///
+/// Returns `None` for text that is not recognizably html, for those the name
+/// should decide. Like Git, we consider a file binary if it has a zero byte in
+/// the first 8000 bytes.
+pub fn sniff(content: &[u8]) -> Option<Sniffed> {
+    let images: [&[u8]; 4] = [b"\x89PNG\r\n\x1a\n", b"\xff\xd8\xff", b"GIF8", b"\0\0\x01\0"];
+    // Riff is a container, only webp is an image.
+    let is_webp = content.starts_with(b"RIFF") && content.get(8..12) == Some(b"WEBP");
+    if is_webp || images.iter().any(|magic| content.starts_with(magic)) {
+        return Some(Sniffed::Image);
+    }
+
+    if content[..content.len().min(8000)].contains(&0) {
+        return Some(Sniffed::Binary);
+    }
+
+    let text = content.strip_prefix(b"\xef\xbb\xbf").unwrap_or(content);
+    let start = text.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(text.len());
+    let starts_with = |tag: &[u8]| {
+        let head = text.get(start..start + tag.len()).unwrap_or(&[]);
+        head.eq_ignore_ascii_case(tag)
+    };
+    if starts_with(b"<!doctype html") || starts_with(b"<html>") || starts_with(b"<html ") {
+        return Some(Sniffed::Html);
+    }
+
+    None
+}
@@ -5,18 +5,36 @@
 // you may not use this file except in compliance with the License.
 // A copy of the License has been included in the root of the repository.
 
-use std::collections::BTreeMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
 use std::io;
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
 
 use git2::build::CheckoutBuilder;
-use git2::{BranchType, ObjectType, Oid, Repository, Tree};
+use git2::{BranchType, Object, ObjectType, Oid, Repository, Tree, TreeEntry};
+
+use minimizer::{
+    compress_deflate, decompress_brotli, decompress_gzip, inline_js_len, minify_html,
+    minify_html_js, minify_html_readable, minify_xhtml, minify_xml, normalize_newlines, sniff,
+    strip_meta, zopfli_iterations_adaptive, Brotli, Compressor, Deflate, Gzip, Sizes, Sniffed,
+};
+
+mod config;
+mod inline;
+mod report;
+#[cfg(feature = "s3")]
+mod s3;
+
+use config::{CacheOrder, Options, PageAlias, Preset, RepoConfig, SourceMoved, REPO_CONFIG_DIR};
 
 type Result<T> = std::result::Result<T, git2::Error>;
 
 /// Blob oids of an html blob that we have already minified in the past.
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 struct MinifiedBlobs {
     /// Oid of the minified html.
     minified: Oid,
@@ -27,45 +45,182 @@ struct MinifiedBlobs {
     /// Oid of the minified and then Brotli-compressed html.
     br: Oid,
 
+    /// Oid of the minified and then raw Deflate-compressed html, if we made it.
+    deflate: Option<Oid>,
+
     /// Stats about the original and compressed file sizes.
     sizes: Sizes,
+
+    /// How long it took to minify and compress, in milliseconds, if we know.
+    millis: Option<u64>,
+
+    /// Fingerprint of the options that produced these blobs, if we know, see
+    /// [`config_fingerprint`].
+    config: Option<Oid>,
+
+    /// When a run last used this entry, in seconds since the Unix epoch, if we
+    /// know.
+    used: Option<u64>,
+
+    /// Bytes of inline JavaScript before and after minifying it, with
+    /// `--minify-js`. This is not in the cache.
+    inline_js: Option<(usize, usize)>,
 }
 
-/// Sizes, in bytes, of an html document in various forms.
-#[derive(Debug, Copy, Clone, Default)]
-struct Sizes {
-    original_len: usize,
-    minified_len: usize,
-    gz_len: usize,
-    br_len: usize,
-}
-
-impl std::fmt::Display for Sizes {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(
-            f,
-            "Original: {}, Minified: {} ({:.1}%), Gzip: {} ({:.1}%), Brotli: {} ({:.1}%)",
-            self.original_len,
-            self.minified_len,
-            100.0 * self.minified_len as f32 / self.original_len as f32,
-            self.gz_len,
-            100.0 * self.gz_len as f32 / self.original_len as f32,
-            self.br_len,
-            100.0 * self.br_len as f32 / self.original_len as f32,
-        )
+/// An html file that was minified as part of the tree.
+struct Page {
+    /// Path of the file, relative to the root of the tree.
+    path: String,
+
+    /// The name by which we classified the page, see [`Options::classify_name`].
+    ///
+    /// With `--content-type` or `--sniff-content`, this has an extension that
+    /// the path may lack.
+    #[cfg_attr(not(feature = "s3"), allow(dead_code))]
+    kind: String,
+
+    /// Oid of the blob in the source tree.
+    source: Oid,
+
+    /// The minified and compressed versions of the source blob.
+    blobs: MinifiedBlobs,
+
+    /// Whether the blobs came from the cache, rather than minifying now.
+    from_cache: bool,
+}
+
+/// Write a manifest that maps source blobs to their minified versions.
+///
+/// This is a tab-separated values document with one row per page, in tree
+/// traversal order. It is useful to trace back which source blob produced a
+/// given minified blob.
+fn write_manifest<W: io::Write>(pages: &[Page], mut out: W) -> io::Result<()> {
+    writeln!(out, "path\tblob\tminified\tgz\tbr")?;
+    for page in pages {
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}",
+            page.path,
+            page.source,
+            page.blobs.minified,
+            page.blobs.gz,
+            page.blobs.br,
+        )?;
+    }
+    out.flush()
+}
+
+/// Write every minified page, and its decompressed gz and br versions, to `dir`.
+///
+/// For a page `index.html`, this writes `index.html` with the minified page,
+/// and `index.html.gz.decoded` and `index.html.br.decoded` with the result of
+/// decompressing the compressed versions, which should be identical. Reports
+/// pages where that is not the case.
+fn dump_decompressed(repo: &Repository, pages: &[Page], dir: &Path) -> Result<()> {
+    for page in pages {
+        let minified = repo.find_blob(page.blobs.minified)?;
+        let gz = repo.find_blob(page.blobs.gz)?;
+        let br = repo.find_blob(page.blobs.br)?;
+        let from_gz = decompress_gzip(gz.content()).expect("Failed to decompress gz.");
+        let from_br = decompress_brotli(br.content()).expect("Failed to decompress br.");
+
+        let out_path = dir.join(&page.path);
+        let out_dir = out_path.parent().expect("Page path has the dump dir as parent.");
+        fs::create_dir_all(out_dir).expect("Failed to create dump directory.");
+        let write = |suffix: &str, content: &[u8]| {
+            let mut fname = out_path.clone().into_os_string();
+            fname.push(suffix);
+            fs::write(&fname, content).expect("Failed to write dump.");
+        };
+        write("", minified.content());
+        write(".gz.decoded", &from_gz[..]);
+        write(".br.decoded", &from_br[..]);
+
+        if from_gz != minified.content() {
+            println!("Decompressed gz differs from minified page: {}", page.path);
+        }
+        if from_br != minified.content() {
+            println!("Decompressed br differs from minified page: {}", page.path);
+        }
+    }
+    Ok(())
+}
+
+/// How often to decompress a page for `--measure-decode`, we report the fastest.
+const DECODE_ROUNDS: u32 = 5;
+
+/// Return the shortest time that `decompress` took out of [`DECODE_ROUNDS`] runs.
+fn time_decode(
+    decompress: fn(&[u8]) -> io::Result<Vec<u8>>,
+    input: &[u8],
+) -> std::time::Duration {
+    (0..DECODE_ROUNDS)
+        .map(|_| {
+            let start = std::time::Instant::now();
+            decompress(input).expect("Failed to decompress a page.");
+            start.elapsed()
+        })
+        .min()
+        .expect("Measure at least one round.")
+}
+
+/// Print how long it takes to decompress the gz and br versions of every page.
+///
+/// Higher compression levels save bytes, but the client still has to decode
+/// them, which on slow devices can cost more than the transfer saves.
+fn print_decode_times(repo: &Repository, pages: &[Page]) -> Result<()> {
+    let mut total_gz = std::time::Duration::ZERO;
+    let mut total_br = std::time::Duration::ZERO;
+    for page in pages {
+        let gz = repo.find_blob(page.blobs.gz)?;
+        let br = repo.find_blob(page.blobs.br)?;
+        let gz_time = time_decode(decompress_gzip, gz.content());
+        let br_time = time_decode(decompress_brotli, br.content());
+        println!(
+            "Decode {}: gz {} bytes in {:.3} ms, br {} bytes in {:.3} ms",
+            page.path,
+            gz.size(),
+            gz_time.as_secs_f64() * 1e3,
+            br.size(),
+            br_time.as_secs_f64() * 1e3,
+        );
+        total_gz += gz_time;
+        total_br += br_time;
     }
+    println!(
+        "Decoding all {} pages takes {:.1} ms for gz, {:.1} ms for br.",
+        pages.len(),
+        total_gz.as_secs_f64() * 1e3,
+        total_br.as_secs_f64() * 1e3,
+    );
+    Ok(())
 }
 
-impl std::ops::Add for Sizes {
-    type Output = Self;
-    fn add(self, other: Self) -> Self {
-        Self {
-            original_len: self.original_len + other.original_len,
-            minified_len: self.minified_len + other.minified_len,
-            gz_len: self.gz_len + other.gz_len,
-            br_len: self.br_len + other.br_len,
+/// Report pages that grew due to minification, return whether the site grew.
+///
+/// The site as a whole grew if the total minified size, or the total size after
+/// Brotli compression, exceeds the total original size. Pages can grow
+/// individually, for example due to the license comment that we add, without
+/// the site as a whole growing.
+fn check_growth(pages: &[Page]) -> bool {
+    let mut total = Sizes::default();
+    for page in pages {
+        let sizes = page.blobs.sizes;
+        if sizes.minified_len > sizes.original_len {
+            println!(
+                "Page grew: {} ({} -> {} bytes)",
+                page.path, sizes.original_len, sizes.minified_len,
+            );
+        }
+        if sizes.br_len > sizes.original_len {
+            println!(
+                "Page grew after Brotli: {} ({} -> {} bytes)",
+                page.path, sizes.original_len, sizes.br_len,
+            );
         }
+        total = total + sizes;
     }
+    total.minified_len > total.original_len || total.br_len > total.original_len
 }
 
 /// A cache of minified and compressed blobs.
@@ -76,52 +231,167 @@ impl std::ops::Add for Sizes {
 struct Cache(BTreeMap<Oid, MinifiedBlobs>);
 
 impl Cache {
-    /// TSV header row for the serialization format.
-    const HEADER: &'static str = "\
-        blob\tblob_len\t\
+    /// TSV header rows of the versions of the serialization format, oldest
+    /// first. We write the latest version, but we can read all of them.
+    const HEADERS: [&'static str; 5] = [
+        "blob\tblob_len\t\
+        minified\tminified_len\t\
+        gz\tgz_len\t\
+        br\tbr_len",
+        // Version 1 added the optional Deflate version.
+        "blob\tblob_len\t\
+        minified\tminified_len\t\
+        gz\tgz_len\t\
+        br\tbr_len\t\
+        deflate\tdeflate_len",
+        // Version 2 added the optional time it took to minify and compress.
+        "blob\tblob_len\t\
+        minified\tminified_len\t\
+        gz\tgz_len\t\
+        br\tbr_len\t\
+        deflate\tdeflate_len\t\
+        millis",
+        // Version 3 added the optional fingerprint of the options.
+        "blob\tblob_len\t\
+        minified\tminified_len\t\
+        gz\tgz_len\t\
+        br\tbr_len\t\
+        deflate\tdeflate_len\t\
+        millis\tconfig",
+        // Version 4 added the optional time the entry was last used.
+        "blob\tblob_len\t\
         minified\tminified_len\t\
         gz\tgz_len\t\
-        br\tbr_len";
+        br\tbr_len\t\
+        deflate\tdeflate_len\t\
+        millis\tconfig\tused",
+    ];
 
     pub fn new() -> Self {
         Self(BTreeMap::new())
     }
 
+    /// Drop entries that refer to blobs that don't exist in the repository.
+    ///
+    /// This happens when the blobs got garbage collected. Returns the number of
+    /// entries dropped, those pages get minified again.
+    fn remove_missing(&mut self, repo: &Repository) -> Result<usize> {
+        let odb = repo.odb()?;
+        let len_before = self.0.len();
+        self.0.retain(|_, v| {
+            [Some(v.minified), Some(v.gz), Some(v.br), v.deflate]
+                .iter()
+                .flatten()
+                .all(|oid| odb.exists(*oid))
+        });
+        Ok(len_before - self.0.len())
+    }
+
+    /// Drop entries that no run used in the last `days` days.
+    ///
+    /// Entries from before we recorded the time count as used now, so they
+    /// expire `days` days from now. Returns the number of entries dropped.
+    fn remove_unused(&mut self, days: u64) -> usize {
+        let now = unix_now();
+        let len_before = self.0.len();
+        self.0.retain(|_, v| now.saturating_sub(*v.used.get_or_insert(now)) <= days * 24 * 3600);
+        len_before - self.0.len()
+    }
+
+    /// Panic if `fresh` has different blobs than this cache for a source blob.
+    fn check_reproduced(&self, fresh: &Cache) {
+        for (k, v) in fresh.0.iter() {
+            let cached = match self.0.get(k) {
+                Some(cached) => cached,
+                None => continue,
+            };
+            let deflate_matches = match (cached.deflate, v.deflate) {
+                (Some(x), Some(y)) => x == y,
+                _ => true,
+            };
+            let matches = cached.minified == v.minified && cached.gz == v.gz && cached.br == v.br;
+            assert!(
+                matches && deflate_matches,
+                "Output for blob {} differs from the cache. Either it was built \
+                with different options or versions, or the output is not reproducible.",
+                k,
+            );
+        }
+    }
+
     /// Serialize the cache into a tab-separated values document.
+    ///
+    /// This writes row by row, the document as a whole is never in memory.
     fn serialize<W: io::Write>(&self, mut out: W) -> std::io::Result<()> {
-        writeln!(out, "{}", Self::HEADER)?;
+        writeln!(out, "{}", Self::HEADERS[Self::HEADERS.len() - 1])?;
         for (k, v) in self.0.iter() {
-            writeln!(
-                out,
-                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
-                k.to_string(),
-                v.sizes.original_len,
-                v.minified.to_string(),
-                v.sizes.minified_len,
-                v.gz.to_string(),
-                v.sizes.gz_len,
-                v.br.to_string(),
-                v.sizes.br_len,
-            )?;
+            Self::serialize_row(&mut out, k, v)?;
         }
         Ok(())
     }
 
+    /// Write the row for one entry, in the latest version of the format.
+    fn serialize_row<W: io::Write>(out: &mut W, k: &Oid, v: &MinifiedBlobs) -> io::Result<()> {
+        // The Deflate version is optional, leave its columns empty if we
+        // don't have it.
+        let (deflate, deflate_len) = match v.deflate {
+            Some(oid) => (oid.to_string(), v.sizes.deflate_len.to_string()),
+            None => (String::new(), String::new()),
+        };
+        let millis = v.millis.map_or(String::new(), |ms| ms.to_string());
+        let config = v.config.map_or(String::new(), |oid| oid.to_string());
+        let used = v.used.map_or(String::new(), |secs| secs.to_string());
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            k,
+            v.sizes.original_len,
+            v.minified,
+            v.sizes.minified_len,
+            v.gz,
+            v.sizes.gz_len,
+            v.br,
+            v.sizes.br_len,
+            deflate,
+            deflate_len,
+            millis,
+            config,
+            used,
+        )
+    }
+
     /// Read the cache from a tab-separated values document.
-    fn deserialize<R: io::BufRead>(input: R) -> std::io::Result<Self> {
+    fn deserialize<R: io::BufRead>(mut input: R) -> std::io::Result<Self> {
         use std::str::FromStr;
 
         let mut result = BTreeMap::new();
-        let mut lines = input.lines();
+        let mut row = String::new();
 
-        // Skip but verify the header row, it is just there for clarity.
-        match lines.next() {
-            None => panic!("Failed to load cache, expected header row."),
-            Some(row) => assert_eq!(row?, Self::HEADER, "Invalid header row."),
+        // The header row tells us the version, and therefore which columns
+        // are present.
+        if input.read_line(&mut row)? == 0 {
+            panic!("Failed to load cache, expected header row.");
         }
+        let header = row.trim_end_matches('\n');
+        let version = Self::HEADERS
+            .iter()
+            .position(|h| *h == header)
+            .expect("Invalid header row.");
 
-        for line_opt in lines {
-            let line = line_opt?;
+        loop {
+            row.clear();
+            if input.read_line(&mut row)? == 0 {
+                break;
+            }
+            // Every row ends in a newline. When the last one does not, a run
+            // crashed while appending to the cache, and the row may be cut off.
+            let line = match row.strip_suffix('\n') {
+                Some(line) => line,
+                None => {
+                    println!("Ignoring the incomplete last row of the cache.");
+                    break;
+                }
+            };
 
             let as_oid = |part: Option<&str>| {
                 Oid::from_str(part.expect("Invalid format, expected oid.")).expect("Invalid oid.")
@@ -140,6 +410,17 @@ impl Cache {
             let gz_len = as_usize(parts.next());
             let br = as_oid(parts.next());
             let br_len = as_usize(parts.next());
+            // Columns that were added later are absent in older versions, and
+            // they are optional, so they can be empty.
+            let mut optional = |since_version: usize| match parts.next() {
+                Some(part) if version >= since_version && !part.is_empty() => Some(part),
+                _ => None,
+            };
+            let deflate = optional(1).map(|part| as_oid(Some(part)));
+            let deflate_len = optional(1).map_or(0, |part| as_usize(Some(part)));
+            let millis = optional(2).map(|part| part.parse().expect("Invalid millis."));
+            let config = optional(3).map(|part| as_oid(Some(part)));
+            let used = optional(4).map(|part| part.parse().expect("Invalid used time."));
 
             result.insert(
                 key,
@@ -147,12 +428,18 @@ impl Cache {
                     minified,
                     gz,
                     br,
+                    deflate,
                     sizes: Sizes {
                         original_len,
                         minified_len,
                         gz_len,
                         br_len,
+                        deflate_len,
                     },
+                    millis,
+                    config,
+                    used,
+                    inline_js: None,
                 },
             );
         }
@@ -161,253 +448,2536 @@ impl Cache {
     }
 
     /// Save the cache to the given tsv file.
-    pub fn save(&self, fname: &str) -> io::Result<()> {
+    ///
+    /// If `gzip` is true, the file is gzip-compressed.
+    pub fn save(&self, fname: &str, gzip: bool) -> io::Result<()> {
         let f = fs::File::create(fname)?;
         let writer = io::BufWriter::new(f);
-        self.serialize(writer)
+        if gzip {
+            // The cache is an artifact that we read once and write once, it is
+            // not worth compressing it with Zopfli.
+            let mut encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::default());
+            self.serialize(&mut encoder)?;
+            encoder.finish()?;
+            Ok(())
+        } else {
+            self.serialize(writer)
+        }
+    }
+
+    /// Append the entries that differ from `saved` to the uncompressed tsv file.
+    ///
+    /// When we load the file, later rows replace earlier ones for the same blob.
+    /// The time an entry was last used does not count as a difference, or we
+    /// would append every entry. Returns false, without writing anything, when
+    /// the file does not exist, or is not in the latest version of the format.
+    /// Then it needs a full save. A crash while appending can leave the last row
+    /// cut off. [`deserialize`](Self::deserialize) ignores such a row, and when
+    /// the file does not end in a newline, we save it in full, without the row.
+    pub fn append(&self, fname: &str, saved: &BTreeMap<Oid, MinifiedBlobs>) -> io::Result<bool> {
+        use std::io::{BufRead, Read, Seek};
+
+        // Read the header as bytes, a gzipped file is not valid UTF-8, and it
+        // needs a full save as well.
+        let mut header = Vec::new();
+        let mut last_byte = [0];
+        match fs::File::open(fname) {
+            Ok(f) => {
+                let mut reader = io::BufReader::new(f);
+                reader.read_until(b'\n', &mut header)?;
+                let latest = Self::HEADERS[Self::HEADERS.len() - 1];
+                if header.strip_suffix(b"\n") != Some(latest.as_bytes()) {
+                    return Ok(false);
+                }
+                reader.seek(io::SeekFrom::End(-1))?;
+                reader.read_exact(&mut last_byte)?;
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        if last_byte != [b'\n'] {
+            return Ok(false);
+        }
+        let f = fs::OpenOptions::new().append(true).open(fname)?;
+        let mut writer = io::BufWriter::new(f);
+        let outputs = |v: &MinifiedBlobs| (v.minified, v.gz, v.br, v.deflate, v.config);
+        for (k, v) in self.0.iter() {
+            if saved.get(k).map_or(true, |old| outputs(old) != outputs(v)) {
+                Self::serialize_row(&mut writer, k, v)?;
+            }
+        }
+        io::Write::flush(&mut writer)?;
+        Ok(true)
     }
 
-    /// Load a cache from the given tsv file.
+    /// Load a cache from the given tsv file, which may be gzip-compressed.
     pub fn load(fname: &str) -> io::Result<Self> {
+        use std::io::BufRead;
+
         let f = fs::File::open(fname)?;
-        let reader = io::BufReader::new(f);
-        Self::deserialize(reader)
+        let mut reader = io::BufReader::new(f);
+        // Detect gzip by its magic bytes, rather than by the file name, so it
+        // does not matter what the file is called.
+        let is_gzip = reader.fill_buf()?.starts_with(&[0x1f, 0x8b]);
+        if is_gzip {
+            let decoder = flate2::bufread::GzDecoder::new(reader);
+            Self::deserialize(io::BufReader::new(decoder))
+        } else {
+            Self::deserialize(reader)
+        }
     }
 }
 
-/// Gzip-compress the input using Zopfli at high compression (slow to run).
-fn compress_zopfli(input: &[u8]) -> Vec<u8> {
-    let opts = zopfli::Options {
-        // Be slow but compress well, only really feasible for small files, but
-        // my html files are small, so that's fine.
-        iteration_count: std::num::NonZeroU8::new(20).unwrap(),
-        // Not sure what this does, use the default value.
-        maximum_block_splits: 15,
+/// Minify and compress a single file, for the `file` subcommand.
+///
+/// This needs no repository and no cache. With `--write`, the minified page
+/// goes next to the file with `.min` before its extension, and the compressed
+/// versions go where the layout templates put them for the file.
+fn minimize_single_file(opts: &Options, fname: &str) {
+    let name = Path::new(fname)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_else(|| panic!("Invalid file name: {}", fname));
+    if !opts.is_page(name) {
+        println!("Not minifying {}, it matches none of the extension lists.", fname);
+        std::process::exit(1);
+    }
+    let content = fs::read(fname).unwrap_or_else(|err| panic!("Failed to read {}: {}", fname, err));
+    let minified = match minify_file(opts, name, &content) {
+        Ok(minified) => normalize_markup(opts, name, minified),
+        Err(err) => {
+            println!("Failed to minify {}: {}", fname, err);
+            std::process::exit(1);
+        }
+    };
+
+    let mut sizes = Sizes {
+        original_len: content.len(),
+        minified_len: minified.len(),
+        ..Sizes::default()
+    };
+    let mut compressed = Vec::new();
+    for compressor in compressors(opts, name, minified.len()) {
+        let bytes = compressor.compress(&minified);
+        let (len, layout) = match compressor.extension() {
+            "gz" => (&mut sizes.gz_len, &opts.layout_gz),
+            "br" => (&mut sizes.br_len, &opts.layout_br),
+            _ => (&mut sizes.deflate_len, &opts.layout_deflate),
+        };
+        *len = bytes.len();
+        if let Some(template) = layout {
+            compressed.push((render_layout(template, fname), bytes));
+        }
+    }
+    println!("{}: {}", fname, report::Colored(&sizes));
+
+    if !opts.write {
+        return;
+    }
+    let path = Path::new(fname);
+    let min_path = match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => path.with_extension(format!("min.{}", ext)),
+        None => path.with_extension("min"),
     };
-    let mut output = Vec::new();
-    let input = std::io::Cursor::new(input);
-    zopfli::compress(&opts, &zopfli::Format::Gzip, input, &mut output)
-        .expect("Zopfli compression should not fail, we don't do IO here.");
+    let min_path = min_path.to_str().expect("Path is UTF-8, it came from a str.").to_string();
+    for (out_path, bytes) in [(min_path, minified)].into_iter().chain(compressed) {
+        if let Some(dir) = Path::new(&out_path).parent() {
+            fs::create_dir_all(dir).expect("Failed to create output directory.");
+        }
+        fs::write(&out_path, bytes)
+            .unwrap_or_else(|err| panic!("Failed to write {}: {}", out_path, err));
+        println!("Wrote {}.", out_path);
+    }
+}
+
+/// Print the entries of the cache, for the `cache-list` subcommand.
+///
+/// Every entry takes two lines: the source blob with its sizes, and then the
+/// blobs of the minified and compressed versions.
+fn list_cache(opts: &Options) {
+    let cache = Cache::load(&opts.cache_path)
+        .unwrap_or_else(|err| panic!("Failed to load {}: {}", opts.cache_path, err));
+    let mut entries: Vec<(&Oid, &MinifiedBlobs)> = cache.0.iter().collect();
+    match opts.cache_order {
+        CacheOrder::Blob => {}
+        CacheOrder::Size => entries.sort_by_key(|(_, v)| std::cmp::Reverse(v.sizes.br_len)),
+        CacheOrder::Savings => {
+            entries.sort_by(|(_, a), (_, b)| b.sizes.br_pct().total_cmp(&a.sizes.br_pct()))
+        }
+    }
+    for (k, v) in &entries {
+        println!("{}: {}", k, v.sizes);
+        let deflate = v.deflate.map_or(String::new(), |oid| format!(", deflate {}", oid));
+        println!("  minified {}, gz {}, br {}{}", v.minified, v.gz, v.br, deflate);
+    }
+    let total = entries.iter().fold(Sizes::default(), |acc, (_, v)| acc + v.sizes);
+    println!("{} entries in {}. Total: {}", entries.len(), opts.cache_path, total);
+}
+
+/// Return the current time in seconds since the Unix epoch.
+fn unix_now() -> u64 {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+    now.expect("The clock should be past 1970.").as_secs()
+}
+
+/// Return the number of Zopfli iterations to use for a minified page.
+fn zopfli_iterations(opts: &Options, minified_len: usize) -> u8 {
+    if opts.zopfli_adaptive {
+        zopfli_iterations_adaptive(minified_len)
+    } else {
+        opts.zopfli_iterations
+    }
+}
 
-    output
+/// Return the compressors to apply to a minified page.
+fn compressors(opts: &Options, name: &str, minified_len: usize) -> Vec<Box<dyn Compressor>> {
+    let iterations = zopfli_iterations(opts, minified_len);
+    let mut result: Vec<Box<dyn Compressor>> = vec![
+        Box::new(Gzip { iterations }),
+        Box::new(Brotli { level: opts.brotli_level(name), mode: opts.brotli_mode(name) }),
+    ];
+    if opts.deflate {
+        result.push(Box::new(Deflate { iterations }));
+    }
+    result
 }
 
-/// Brotli-compress the input at maximum compression level.
-fn compress_brotli(input: &[u8]) -> Vec<u8> {
-    use io::Write;
-    let level = 11;
-    let mut encoder = brotli2::write::BrotliEncoder::new(Vec::new(), level);
-    encoder
-        .write_all(input)
-        .expect("No IO happens here, should not fail.");
-    encoder
-        .finish()
-        .expect("No IO happens here, should not fail.")
+/// The number of slow compressions that timed out and still run, for `--blob-timeout`.
+static DETACHED_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Compress on a separate thread, and if that takes longer than `secs`, fall
+/// back to fast compression. Returns the bytes, and whether we fell back.
+///
+/// We can't interrupt the thread, it keeps running until it finishes, but
+/// we don't wait for it. Those threads no longer hold a compress slot, so at
+/// most `max_detached` of them may run, after that we wait for the slow one.
+fn compress_with_timeout(
+    id: Oid,
+    compressor: Box<dyn Compressor>,
+    input: &[u8],
+    secs: u64,
+    max_detached: usize,
+) -> (Vec<u8>, bool) {
+    use std::sync::{mpsc, Arc};
+
+    let compressor: Arc<dyn Compressor> = Arc::from(compressor);
+    let (sender, receiver) = mpsc::channel();
+    let slow = Arc::clone(&compressor);
+    let slow_input = input.to_vec();
+    // Once we give up on the thread, it releases its count of detached threads
+    // when it finishes. The lock ensures that it either sends its result, or
+    // sees that we gave up, and never neither.
+    let is_detached = Arc::new(Mutex::new(false));
+    let slow_is_detached = Arc::clone(&is_detached);
+    thread::spawn(move || {
+        let bytes = slow.compress(&slow_input);
+        let is_detached = slow_is_detached.lock().unwrap();
+        if *is_detached {
+            DETACHED_THREADS.fetch_sub(1, Ordering::SeqCst);
+        } else {
+            sender.send(bytes).ok();
+        }
+    });
+
+    let failed = format!("Failed to compress {:?} to {}.", id, compressor.extension());
+    match receiver.recv_timeout(std::time::Duration::from_secs(secs)) {
+        Ok(bytes) => (bytes, false),
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            let mut is_detached = is_detached.lock().unwrap();
+            if let Ok(bytes) = receiver.try_recv() {
+                return (bytes, false);
+            }
+            let claimed = DETACHED_THREADS
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                    (n < max_detached).then(|| n + 1)
+                })
+                .is_ok();
+            let ext = compressor.extension();
+            if !claimed {
+                drop(is_detached);
+                println!("\r{:?}: {} took over {}s, waiting for it.", id, ext, secs);
+                return (receiver.recv().expect(&failed), false);
+            }
+            *is_detached = true;
+            drop(is_detached);
+            println!("\r{:?}: {} took over {}s, using fast compression.", id, ext, secs);
+            (compressor.compress_fast(input), true)
+        }
+        Err(mpsc::RecvTimeoutError::Disconnected) => panic!("{}", failed),
+    }
 }
 
-/// Minify html and embedded CSS. Preserves a license comment.
-fn minify_html(input: &[u8]) -> Vec<u8> {
-    use std::str;
+/// Run an external minifier command, with the input on stdin.
+///
+/// The command is split on whitespace, the first part is the program.
+fn run_minifier(command: &str, input: &[u8]) -> io::Result<Vec<u8>> {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let mut parts = command.split_whitespace();
+    let program = parts.next().expect("Minifier command should not be empty.");
+    let mut child = Command::new(program)
+        .args(parts)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
 
-    let cfg = minify_html::Cfg {
-        do_not_minify_doctype: true,
-        ensure_spec_compliant_unquoted_attribute_values: true,
-        keep_closing_tags: true,
-        keep_html_and_head_opening_tags: true,
-        keep_spaces_between_attributes: true,
-        keep_comments: false,
-        minify_css: true,
-        minify_js: false,
-        remove_bangs: false,
-        remove_processing_instructions: true,
+    // Write on a separate thread, or we might block on a full stdout pipe
+    // while the child blocks on a full stdin pipe.
+    let mut stdin = child.stdin.take().expect("We requested a pipe for stdin.");
+    let output = thread::scope(|scope| {
+        let writer = scope.spawn(move || stdin.write_all(input));
+        let output = child.wait_with_output();
+        writer.join().expect("Writing to the minifier should not panic.")?;
+        output
+    })?;
+
+    if !output.status.success() {
+        let message = format!("{} exited with {}", program, output.status);
+        return Err(io::Error::new(io::ErrorKind::Other, message));
+    }
+    Ok(output.stdout)
+}
+
+/// Minify the file with the given name, with an external minifier if one is
+/// configured for its extension, or as html otherwise.
+///
+/// Unless `--strict` is set, when minification fails, return the original.
+fn minify_file(opts: &Options, name: &str, content: &[u8]) -> std::result::Result<Vec<u8>, String> {
+    if opts.is_compress_only(name) {
+        return Ok(content.to_vec());
+    }
+    if opts.is_xml(name) {
+        return minify_xml(content).ok_or_else(|| "not well-formed XML".to_string());
+    }
+    if let Some(command) = opts.external_minifier(name) {
+        return run_minifier(command, content).map_err(|err| format!("{}: {}", command, err));
+    }
+    let is_xhtml = opts.is_xhtml(name);
+    let minify = || {
+        if is_xhtml {
+            minify_xhtml(content, &opts.banner)
+        } else if opts.preset == Preset::Staging {
+            minify_html_readable(content)
+        } else if opts.minify_js {
+            minify_html_js(content, &opts.banner)
+        } else {
+            minify_html(content, &opts.banner)
+        }
+    };
+    let minified = if opts.strict {
+        minify()
+    } else {
+        // A bug in the minifier should not fail the entire site, we can still
+        // compress the page as it is. The panic hook prints the message.
+        std::panic::catch_unwind(minify).map_err(|_| "minification panicked".to_string())?
     };
 
-    let minified_bytes = minify_html::minify(input, &cfg);
+    // Check before we strip elements on purpose, those would fail the checks.
+    sanity_check(opts, content, &minified)?;
+
+    if is_xhtml || opts.strip_meta.is_empty() {
+        return Ok(minified);
+    }
+    let html = std::str::from_utf8(&minified).expect("Minified html should be UTF-8.");
+    match strip_meta(html, &opts.strip_meta) {
+        Some(stripped) => Ok(stripped.into_bytes()),
+        None => Ok(minified),
+    }
+}
 
-    let minified_str = str::from_utf8(&minified_bytes[..])
-        .expect("File should be valid UTF-8.");
+/// Run the sanity checks on an html page and its minified version.
+fn sanity_check(
+    opts: &Options,
+    original: &[u8],
+    minified: &[u8],
+) -> std::result::Result<(), String> {
+    if opts.sanity_checks.is_empty() {
+        return Ok(());
+    }
+    // Minification panics on invalid UTF-8 already, so here both are valid.
+    let original = std::str::from_utf8(original).map_err(|err| err.to_string())?;
+    let minified = std::str::from_utf8(minified).map_err(|err| err.to_string())?;
+    for check in &opts.sanity_checks {
+        check.check(original, minified).map_err(|err| format!("sanity check failed: {}", err))?;
+    }
+    Ok(())
+}
 
-    // Put back the copyright notices that minification would strip.
-    minified_str.replace(
-        "<html><head>",
-        "<html><!--\n\
-        Kilsbergen MkDocs theme copyright 2022 Ruud van Asseldonk,\n\
-        licensed Apache 2.0, https://github.com/ruuda/kilsbergen.\n\
-        Inter font family copyright Rasmus Andersson,\n\
-        licensed SIL OFL 1.1, https://rsms.me/inter/.\n--><head>"
-    ).into_bytes()
+/// Return a hash of the options that affect the output for a file named `name`.
+///
+/// The hash covers how we minify files of this kind, and how we compress them,
+/// except for Deflate, which we can add to a cached entry later. Options for
+/// other kinds of files don't affect it.
+fn config_fingerprint(opts: &Options, name: &str) -> Oid {
+    let minifier = if opts.is_compress_only(name) {
+        "compress".to_string()
+    } else if opts.is_xml(name) {
+        "xml".to_string()
+    } else if let Some(command) = opts.external_minifier(name) {
+        format!("external {:?}", command)
+    } else {
+        format!(
+            "{} banner={:?} minify_js={} staging={} strip_meta={:?}",
+            if opts.is_xhtml(name) { "xhtml" } else { "html" },
+            opts.banner,
+            opts.minify_js,
+            opts.preset == Preset::Staging,
+            opts.strip_meta,
+        )
+    };
+    let is_markup = opts.is_html(name) || opts.is_xhtml(name) || opts.is_xml(name);
+    let normalize_newlines = match opts.external_minifier(name) {
+        None if is_markup => opts.normalize_newlines,
+        _ => None,
+    };
+    let zopfli = match opts.zopfli_adaptive {
+        true => "adaptive".to_string(),
+        false => opts.zopfli_iterations.to_string(),
+    };
+    let config = format!(
+        "{}\nnormalize_newlines={:?}\nzopfli={}\nbrotli={} {:?}\n",
+        minifier,
+        normalize_newlines,
+        zopfli,
+        opts.brotli_level(name),
+        opts.brotli_mode(name),
+    );
+    Oid::hash_object(ObjectType::Blob, config.as_bytes()).expect("Failed to hash the options.")
 }
 
-/// Minimize and compress a blob that contains html.
-fn minimize_blob(repo: &Repository, id: Oid) -> Result<MinifiedBlobs> {
-    let blob = repo.find_blob(id)?;
+/// Store the bytes as a blob, with `--verify-writes` also read them back.
+fn write_blob(opts: &Options, repo: &Repository, bytes: &[u8]) -> Result<Oid> {
+    let oid = repo.blob(bytes)?;
+    if opts.verify_writes && repo.find_blob(oid)?.content() != bytes {
+        let message = format!("Blob {} does not read back as what we wrote.", oid);
+        return Err(git2::Error::from_str(&message));
+    }
+    Ok(oid)
+}
 
+/// Apply `--normalize-newlines` to a minified page, if it is markup.
+fn normalize_markup(opts: &Options, name: &str, minified: Vec<u8>) -> Vec<u8> {
+    // Other pages may be binary, and external minifiers know their format.
+    let is_markup = opts.is_html(name) || opts.is_xhtml(name) || opts.is_xml(name);
+    match opts.normalize_newlines {
+        Some(trailing) if is_markup && opts.external_minifier(name).is_none() => {
+            normalize_newlines(&minified, trailing)
+        }
+        _ => minified,
+    }
+}
 
-    let mut stdout = std::io::stdout().lock();
-    let mut print_status = |status| {
+/// Minimize and compress a page, store the results as blobs.
+///
+/// The `id` is the oid of the page blob, it is only used for status output.
+/// The `name` of the file determines how it is minified. Without `minify`, the
+/// minified version is the content as it is. Also returns whether we fell back
+/// to fast compression, with `--blob-timeout`.
+fn minimize_blob(
+    opts: &Options,
+    repo: &Repository,
+    id: Oid,
+    name: &str,
+    content: &[u8],
+    minify: bool,
+) -> Result<(MinifiedBlobs, bool)> {
+    let print_status = |status: &str| {
         use std::io::Write;
+        let mut stdout = std::io::stdout().lock();
         write!(stdout, "\r{:?}: {}", id, status).unwrap();
         stdout.flush().unwrap();
     };
+    // When multiple threads compress, their progress would overwrite each
+    // other's line, so then we only print the final status.
+    let print_progress = |status: &str| {
+        if opts.threads_walk.min(opts.threads_compress) == 1 {
+            print_status(status);
+        }
+    };
+
+    let start = std::time::Instant::now();
+    print_progress("minify");
+    let (minified_bytes, is_minified) = match minify.then(|| minify_file(opts, name, content)) {
+        None => (content.to_vec(), false),
+        Some(Ok(bytes)) => (bytes, true),
+        Some(Err(err)) if opts.strict => panic!("Failed to minify {:?}: {}", id, err),
+        Some(Err(err)) => {
+            print_status(&format!("minification failed, using the original page: {}\n", err));
+            (content.to_vec(), false)
+        }
+    };
+
+    // To see what minifying scripts gains, minify once more without it, and
+    // compare the scripts. The other differences are the same for both.
+    let inline_js = match std::str::from_utf8(&minified_bytes) {
+        Ok(html) if opts.minify_js && opts.is_html(name) && is_minified => {
+            let without = minify_html(content, &opts.banner);
+            let without = std::str::from_utf8(&without).expect("Minified html should be UTF-8.");
+            Some((inline_js_len(without), inline_js_len(html)))
+        }
+        _ => None,
+    };
+
+    let minified_bytes = match minify {
+        true => normalize_markup(opts, name, minified_bytes),
+        false => minified_bytes,
+    };
 
-    print_status("minify");
-    let minified_bytes = minify_html(blob.content());
-    print_status("zopfli");
-    let gz_bytes = compress_zopfli(&minified_bytes[..]);
-    print_status("brotli");
-    let br_bytes = compress_brotli(&minified_bytes[..]);
-    print_status("complete\n");
+    // Store every compressed version in a blob, by extension.
+    let mut compressed = BTreeMap::new();
+    let mut is_fallback = false;
+    for compressor in compressors(opts, name, minified_bytes.len()) {
+        let ext = compressor.extension().to_string();
+        print_progress(&ext);
+        let bytes = match opts.blob_timeout_secs {
+            Some(secs) => {
+                let max_detached = opts.threads_compress;
+                let (bytes, fell_back) =
+                    compress_with_timeout(id, compressor, &minified_bytes, secs, max_detached);
+                is_fallback |= fell_back;
+                bytes
+            }
+            None => {
+                let bytes = compressor.compress(&minified_bytes[..]);
+                // A difference would point at state that leaks between calls
+                // of the encoder, or between threads.
+                if opts.check_determinism {
+                    print_progress(&format!("{} again", ext));
+                    assert!(
+                        compressor.compress(&minified_bytes[..]) == bytes,
+                        "Compressing {:?} to {} twice gave different results.",
+                        id,
+                        ext,
+                    );
+                }
+                bytes
+            }
+        };
+        let oid = write_blob(opts, repo, &bytes)?;
+        compressed.insert(ext, (oid, bytes.len()));
+    }
+    let (gz, gz_len) = compressed["gz"];
+    let (br, br_len) = compressed["br"];
+    let deflate = compressed.get("deflate").copied();
 
-    // Store the minified version in a blob.
     let result = MinifiedBlobs {
-        minified: repo.blob(&minified_bytes[..])?,
-        gz: repo.blob(&gz_bytes[..])?,
-        br: repo.blob(&br_bytes[..])?,
+        minified: write_blob(opts, repo, &minified_bytes)?,
+        gz,
+        br,
+        deflate: deflate.map(|(oid, _)| oid),
         sizes: Sizes {
-            original_len: blob.size(),
+            original_len: content.len(),
             minified_len: minified_bytes.len(),
-            gz_len: gz_bytes.len(),
-            br_len: br_bytes.len(),
+            gz_len,
+            br_len,
+            deflate_len: deflate.map_or(0, |(_, len)| len),
         },
+        millis: Some(start.elapsed().as_millis() as u64),
+        config: Some(config_fingerprint(opts, name)),
+        used: Some(unix_now()),
+        inline_js,
     };
 
-    Ok(result)
+    Ok((result, is_fallback))
+}
+
+/// Called with the path and sizes of every page that we minified, rather than
+/// took from the cache. The minimizer does not print the final status of the
+/// page itself, so the caller can report it however it likes.
+///
+/// This is internal to the binary. The tree walk depends on the options, the
+/// cache, and the threads of a run, so the library does not expose it, and
+/// embedders can't pass a callback. They get the functions for single files.
+type OnPage<'a> = dyn FnMut(&Path, &Sizes) + Send + 'a;
+
+/// State shared by the threads that minimize a tree.
+struct Walk<'a> {
+    opts: &'a Options,
+
+    /// The cache, we only hold the lock for lookups and inserts.
+    cache: Mutex<&'a mut Cache>,
+
+    /// Path of the repository, for threads to open their own handle to it.
+    repo_path: &'a Path,
+
+    /// Oid of the root of the source tree.
+    root: Oid,
+
+    /// The number of threads that we may spawn in addition to the running ones.
+    spare_threads: AtomicUsize,
+
+    /// The number of pages that we may still minimize, for `--limit`.
+    pages_left: AtomicUsize,
+
+    /// The number of threads that may start to minify and compress a page.
+    compress_slots: Mutex<usize>,
+
+    /// Signalled when a thread is done compressing and returns its slot.
+    compress_done: Condvar,
+
+    /// Called for every page that we minimized, one thread at a time.
+    on_page: Mutex<&'a mut OnPage<'a>>,
+
+    /// The branch that we walk, and where to log pages to, with `--log-jsonl`.
+    log: Option<(&'a str, &'a Mutex<fs::File>)>,
+}
+
+impl<'a> Walk<'a> {
+    /// Claim one of the spare threads, return whether that succeeded.
+    fn claim_thread(&self) -> bool {
+        self.spare_threads
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok()
+    }
+
+    /// Return a thread claimed with [`claim_thread`](Self::claim_thread).
+    fn release_thread(&self) {
+        self.spare_threads.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Wait until fewer than `--threads-compress` threads are compressing, then
+    /// run `f`. Walking continues on other threads in the meantime.
+    fn compress<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        let mut slots = self.compress_slots.lock().unwrap();
+        while *slots == 0 {
+            slots = self.compress_done.wait(slots).unwrap();
+        }
+        *slots -= 1;
+        drop(slots);
+
+        // Return the slot also when `f` panics, or the other threads would
+        // wait forever, and the panic would never reach the main thread.
+        struct Slot<'w, 'a>(&'w Walk<'a>);
+        impl<'w, 'a> Drop for Slot<'w, 'a> {
+            fn drop(&mut self) {
+                *self.0.compress_slots.lock().unwrap() += 1;
+                self.0.compress_done.notify_one();
+            }
+        }
+        let _slot = Slot(self);
+        f()
+    }
+
+    /// Count a page against the limit, return whether it was within the limit.
+    fn claim_page(&self) -> bool {
+        self.pages_left
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok()
+    }
 }
 
 /// Like [`minimize_blob`], but return blobs from the cache if possible.
 ///
-/// Also fills the cache for blobs that we minimized/compressed for the first
-/// time.
-fn minimize_blob_cached<'a>(
-    cache: &'a mut Cache,
+/// Returns whether the blobs came from the cache as well. Also fills the
+/// cache for blobs that we minimized/compressed for the first time. If
+/// `content` is `None`, the html is the content of the blob `id`, otherwise
+/// `id` is the cache key for `content`, such as the oid that a blob with
+/// `content` would have. Without `minify`, we only compress the content.
+fn minimize_blob_cached(
+    walk: &Walk,
     repo: &Repository,
     id: Oid,
-) -> Result<&'a MinifiedBlobs> {
-    use std::collections::btree_map::Entry;
+    name: &str,
+    content: Option<&[u8]>,
+    minify: bool,
+) -> Result<(MinifiedBlobs, bool)> {
+    let opts = walk.opts;
 
-    let blobs = match cache.0.entry(id) {
-        Entry::Occupied(o) => o.into_mut(),
-        Entry::Vacant(v) => v.insert(minimize_blob(repo, id)?),
+    // Don't hold the lock while we compress, so other threads can proceed.
+    // Two threads may end up minimizing the same blob, but the result is the
+    // same either way.
+    let cached = walk.cache.lock().unwrap().0.get(&id).copied();
+    // Entries without a fingerprint predate it, we can't tell, so those count
+    // as changed too.
+    let cached = match cached {
+        Some(c) if opts.only_changed_extensions => {
+            Some(c).filter(|c| c.config == Some(config_fingerprint(opts, name)))
+        }
+        _ => cached,
+    };
+    let (mut blobs, is_fallback) = match (cached, content) {
+        (Some(blobs), _) => (blobs, false),
+        (None, Some(content)) => {
+            walk.compress(|| minimize_blob(opts, repo, id, name, content, minify))?
+        }
+        // Load the blob only once we have a slot, so no more than
+        // `--threads-compress` pages are in memory at once, however many
+        // threads are walking.
+        (None, None) => walk.compress(|| {
+            let blob = repo.find_blob(id)?;
+            minimize_blob(opts, repo, id, name, blob.content(), minify)
+        })?,
     };
 
-    Ok(blobs)
-}
+    // Entries from before we enabled Deflate lack that version, but we can
+    // produce it from the minified blob without minifying again.
+    if opts.deflate && blobs.deflate.is_none() {
+        let deflate_len = walk.compress(|| -> Result<usize> {
+            let minified = repo.find_blob(blobs.minified)?;
+            let iterations = zopfli_iterations(opts, minified.size());
+            let deflate_bytes = compress_deflate(minified.content(), iterations);
+            blobs.deflate = Some(write_blob(opts, repo, &deflate_bytes)?);
+            Ok(deflate_bytes.len())
+        })?;
+        blobs.sizes.deflate_len = deflate_len;
+    }
 
-/// Given a Git tree, make a copy where all html files are compressed.
-///
-/// This minifies .html files, and adds a Gzip and Brotli compressed version as
-/// well. Non-interesting files are dropped from the tree.
-fn minimize_tree(
-    cache: &mut Cache,
-    sizes: &mut Sizes,
-    repo: &Repository,
-    tree: &Tree,
-    depth: u32,
-) -> Result<Option<Oid>> {
-    let base_tree = None;
-    let mut builder = repo.treebuilder(base_tree)?;
+    // Update the entry also on a hit, to record that we used it. The fast
+    // fallback is only for this run, the next run tries the slow one again.
+    blobs.used = Some(unix_now());
+    if !is_fallback {
+        walk.cache.lock().unwrap().0.insert(id, blobs);
+    }
 
-    let filemode_directory = 0o040000;
-    let filemode_regular = 0o0100644;
+    Ok((blobs, cached.is_some()))
+}
 
-    for entry in tree.iter() {
-        let name = entry.name().expect("Invalid name in tree entry.");
+/// Minimize the html or other page in `entry` of the tree at `path`.
+fn minimize_page(
+    walk: &Walk,
+    repo: &Repository,
+    root: &Tree,
+    entry: &TreeEntry,
+    path: &str,
+    kind: &str,
+) -> Result<Page> {
+    let opts = walk.opts;
+    let name = entry.name().expect("Invalid name in tree entry.");
+    let file_path = format!("{path}{name}");
 
-        match entry.kind() {
-            Some(ObjectType::Tree) => {
-                // Skip the theme, MkDocs includes this because I put the theme
-                // in a subdirectory of the docs, but it really shouldn't be
-                // there.
-                if name == "theme" && depth == 0 {
-                    continue;
-                }
+    // Pages that we don't minify have a key of their own, so the cache does
+    // not mix them up with minified versions of the same content.
+    let minify = !opts.is_no_minify(&file_path);
+    let mut key = entry.id();
+    let mut original = None;
+    if !minify {
+        key = Oid::hash_object(ObjectType::Blob, format!("no-minify {}", key).as_bytes())?;
+        original = Some(repo.find_blob(entry.id())?);
+    }
 
-                let subtree = repo.find_tree(entry.id())?;
-                if let Some(sub_oid) = minimize_tree(cache, sizes, repo, &subtree, depth + 1)? {
-                    builder.insert(name, sub_oid, filemode_directory)?;
-                }
-            }
-            Some(ObjectType::Blob) => {
-                if name.ends_with(".html") {
-                    let blobs = minimize_blob_cached(cache, repo, entry.id())?;
-                    builder.insert(name, blobs.minified, filemode_regular)?;
-                    builder.insert(format!("{name}.gz"), blobs.gz, filemode_regular)?;
-                    builder.insert(format!("{name}.br"), blobs.br, filemode_regular)?;
-                    *sizes = *sizes + blobs.sizes;
-                }
-                if name.ends_with(".png") || name.ends_with(".jpg") {
-                    builder.insert(name, entry.id(), filemode_regular)?;
-                }
+    // When we inline assets, the result depends on more than just the page, so
+    // then we key the cache on the inlined page.
+    let mut inlined = None;
+    if let (true, true, Some(max_bytes)) = (minify, opts.is_html(kind), opts.inline_max_bytes) {
+        let blob = repo.find_blob(entry.id())?;
+        // Inlining works on text. A page that is not UTF-8 goes to the minifier
+        // without inlining, which reports the failure, rather than aborting here.
+        if let Ok(page) = std::str::from_utf8(blob.content()) {
+            if let Some(page) = inline::inline_assets(repo, root, path, page, max_bytes)? {
+                key = Oid::hash_object(ObjectType::Blob, page.as_bytes())?;
+                inlined = Some(page);
             }
-            ot => panic!("Unexpected object type in tree: {:?}", ot),
         }
     }
+    let content = match (&inlined, &original) {
+        (Some(page), _) => Some(page.as_bytes()),
+        (None, Some(blob)) => Some(blob.content()),
+        (None, None) => None,
+    };
+    let start = std::time::Instant::now();
+    let (mut blobs, from_cache) = minimize_blob_cached(walk, repo, key, kind, content, minify)?;
+    let duration = start.elapsed();
 
-    if builder.is_empty() {
-        Ok(None)
-    } else {
-        let tree_oid = builder.write()?;
-        Ok(Some(tree_oid))
+    // The cache may have a Deflate version from an earlier run, but if we
+    // don't want it now, it should not count at all.
+    if !opts.deflate {
+        blobs.deflate = None;
+        blobs.sizes.deflate_len = 0;
     }
-}
 
-fn minimize(cache: &mut Cache, repo: &Repository) -> Result<Oid> {
-    let pages_branch = repo.find_branch("gh-pages", BranchType::Local)?;
-    println!("Branch gh-pages -> {:?}", pages_branch.get().target().unwrap());
-    let tree = pages_branch.get().peel_to_tree()?;
+    let page = Page {
+        path: file_path,
+        kind: kind.to_string(),
+        source: entry.id(),
+        blobs,
+        from_cache,
+    };
+    if !from_cache {
+        (walk.on_page.lock().unwrap())(Path::new(&page.path), &blobs.sizes);
+    }
+    if let Some((branch, log)) = walk.log {
+        log_page(&mut *log.lock().unwrap(), branch, &page, duration)
+            .unwrap_or_else(|err| panic!("Failed to write to --log-jsonl: {}", err));
+    }
 
-    let initial_depth = 0;
-    let mut sizes = Sizes::default();
-    let tree_min = minimize_tree(cache, &mut sizes, repo, &tree, initial_depth)?.expect("Must have a root tree.");
-    println!("Minimized tree  -> {:?}", tree_min);
-    println!("{}", sizes);
+    if opts.exceeds_budget(&blobs.sizes) {
+        println!(
+            "Page exceeds budget: {} ({} bytes Brotli)",
+            page.path, blobs.sizes.br_len,
+        );
+    }
 
-    Ok(tree_min)
+    Ok(page)
 }
 
-/// Check out the given tree at the given path.
+/// Write the line for `--log-jsonl` about a page that we processed.
 ///
-/// This is a destructive function that clears whatever is currently at that
-/// path.
-fn checkout_into<P: AsRef<Path>>(repo: &Repository, root: Oid, target_dir: P) -> Result<()> {
-    let mut checkout_builder = CheckoutBuilder::new();
-    checkout_builder
-        .target_dir(target_dir.as_ref())
-        .update_index(false)
-        .remove_ignored(true)
-        .remove_untracked(true)
-        .force();
-    let root_obj = repo.find_object(root, Some(ObjectType::Tree))?;
-    repo.checkout_tree(&root_obj, Some(&mut checkout_builder))
+/// The line goes out in one write, so a crash leaves no half lines.
+fn log_page(
+    out: &mut fs::File,
+    branch: &str,
+    page: &Page,
+    duration: std::time::Duration,
+) -> io::Result<()> {
+    let blobs = &page.blobs;
+    let deflate = blobs.deflate.map_or("null".to_string(), |oid| format!("\"{}\"", oid));
+    let line = format!(
+        "{{\"schema_version\":{},\"branch\":{},\"path\":{},\"source\":\"{}\",\
+        \"minified\":\"{}\",\"gz\":\"{}\",\"br\":\"{}\",\"deflate\":{},\
+        \"original_len\":{},\"minified_len\":{},\"gz_len\":{},\"br_len\":{},\
+        \"deflate_len\":{},\"cache_hit\":{},\"duration_ms\":{:.3}}}\n",
+        report::LOG_SCHEMA_VERSION,
+        report::json_string(branch),
+        report::json_string(&page.path),
+        page.source,
+        blobs.minified,
+        blobs.gz,
+        blobs.br,
+        deflate,
+        blobs.sizes.original_len,
+        blobs.sizes.minified_len,
+        blobs.sizes.gz_len,
+        blobs.sizes.br_len,
+        blobs.sizes.deflate_len,
+        page.from_cache,
+        duration.as_secs_f64() * 1000.0,
+    );
+    io::Write::write_all(out, line.as_bytes())
 }
 
-fn main() -> Result<()> {
-    let mut args = std::env::args();
-    // Skip the program name.
-    args.next();
+/// Return the size of a blob, without reading it, these files can be large.
+fn blob_size(repo: &Repository, id: Oid) -> Result<usize> {
+    let (size, _kind) = repo.odb()?.read_header(id)?;
+    Ok(size)
+}
 
-    let repo_path = args.next().expect("Expected repository path.");
-    let repo = Repository::open(repo_path)?;
+/// Return the total size of the blobs in the tree, including subtrees.
+fn tree_size(repo: &Repository, tree: &Tree) -> Result<usize> {
+    let mut total = 0;
+    for entry in tree.iter() {
+        total += match entry.kind() {
+            Some(ObjectType::Tree) => tree_size(repo, &repo.find_tree(entry.id())?)?,
+            _ => blob_size(repo, entry.id())?,
+        };
+    }
+    Ok(total)
+}
 
-    let target_path = args.next().expect("Expected target path.");
+/// Return whether a file to copy is larger than `--max-asset-bytes`.
+fn is_too_large(opts: &Options, repo: &Repository, id: Oid) -> Result<bool> {
+    match opts.max_asset_bytes {
+        Some(max_bytes) => Ok(blob_size(repo, id)? > max_bytes),
+        None => Ok(false),
+    }
+}
 
-    let mut cache = match Cache::load("cache.tsv") {
-        Ok(cache) => cache,
-        Err(_) => {
-            println!("Starting with empty cache, cache failed to load.");
-            Cache::new()
-        }
+/// Fill in a layout template for the page at `path`.
+///
+/// `{path}` is the path of the page, `{dir}` the directory that contains it,
+/// with trailing slash, and `{name}` its file name. `{stem}` is the file name
+/// without extension, and `{ext}` the extension with dot, so the compressed
+/// version can go before the extension, as in `{dir}{stem}.gz{ext}`.
+fn render_layout(template: &str, path: &str) -> String {
+    let (dir, name) = match path.rsplit_once('/') {
+        Some((dir, name)) => (format!("{}/", dir), name),
+        None => (String::new(), path),
     };
+    // A leading dot, as in `.htaccess`, does not start an extension.
+    let (stem, ext) = match name.rfind('.') {
+        Some(i) if i > 0 => name.split_at(i),
+        _ => (name, ""),
+    };
+    template
+        .replace("{path}", path)
+        .replace("{dir}", &dir)
+        .replace("{name}", name)
+        .replace("{stem}", stem)
+        .replace("{ext}", ext)
+}
 
-    let root_tree = minimize(&mut cache, &repo)?;
+/// Add the blobs to the tree at the given paths, creating directories as needed.
+fn insert_paths(repo: &Repository, tree: Option<&Tree>, files: &[(String, Oid)]) -> Result<Oid> {
+    let filemode_directory = 0o040000;
+    let filemode_regular = 0o0100644;
 
-    cache.save("cache.tsv.new").expect("Failed to save cache.");
-    std::fs::rename("cache.tsv.new", "cache.tsv").expect("Failed to move cache.");
+    let mut builder = repo.treebuilder(tree)?;
+    let mut subdirs: BTreeMap<&str, Vec<(String, Oid)>> = BTreeMap::new();
 
-    // TODO: Create a ref to avoid the root getting GC'd.
+    for (path, oid) in files {
+        match path.split_once('/') {
+            None => {
+                builder.insert(path, *oid, filemode_regular)?;
+            }
+            Some((dir, rest)) => subdirs
+                .entry(dir)
+                .or_default()
+                .push((rest.to_string(), *oid)),
+        }
+    }
+
+    for (dir, files) in subdirs {
+        let existing = builder.get(dir)?.map(|entry| entry.id());
+        let subtree = match existing {
+            Some(id) => Some(repo.find_tree(id)?),
+            None => None,
+        };
+        let sub_oid = insert_paths(repo, subtree.as_ref(), &files)?;
+        builder.insert(dir, sub_oid, filemode_directory)?;
+    }
+
+    builder.write()
+}
+
+/// Add a copy of every html page where the other URL convention looks for it.
+///
+/// For [`PageAlias::Dir`], `foo.html` also goes to `foo/index.html`, and for
+/// [`PageAlias::Flat`] the other way around. Compressed versions next to the
+/// page, such as `foo.html.gz`, come along. The copies share the blob, so they
+/// are cheap. Where a file exists already, we leave it.
+fn add_page_aliases(repo: &Repository, root: Oid, alias: PageAlias) -> Result<Oid> {
+    let tree = repo.find_tree(root)?;
+    let mut blobs = BTreeMap::new();
+    tree.walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+        if entry.kind() == Some(ObjectType::Blob) {
+            let path = format!("{}{}", dir, entry.name().expect("Invalid name in tree entry."));
+            blobs.insert(path, entry.id());
+        }
+        git2::TreeWalkResult::Ok
+    })?;
+
+    let mut files = Vec::new();
+    for (path, oid) in &blobs {
+        let (dir, name) = match path.rfind('/') {
+            Some(i) => path.split_at(i + 1),
+            None => ("", &path[..]),
+        };
+        // The rest is empty for the page, or an extension such as `.gz`.
+        let (stem, rest) = match name.split_once(".html") {
+            Some((stem, rest)) if rest.is_empty() || rest.starts_with('.') => (stem, rest),
+            _ => continue,
+        };
+        let alias_path = match alias {
+            PageAlias::Dir if stem != "index" && !blobs.contains_key(&format!("{dir}{stem}")) => {
+                format!("{dir}{stem}/index.html{rest}")
+            }
+            PageAlias::Flat if stem == "index" && !dir.is_empty() => {
+                format!("{}.html{}", dir.trim_end_matches('/'), rest)
+            }
+            _ => continue,
+        };
+        if !blobs.contains_key(&alias_path) {
+            files.push((alias_path, *oid));
+        }
+    }
+
+    if files.is_empty() {
+        return Ok(root);
+    }
+    println!("Adding {} aliases of pages.", files.len());
+    insert_paths(repo, Some(&tree), &files)
+}
+
+/// Add the Gzip, Brotli, and Deflate versions of the pages to the minimized tree.
+///
+/// They go where the layout templates put them, by default next to the page.
+///
+/// With `--no-plain`, the tree can be `None`, because it would only contain
+/// pages that we leave out. It is an error when a layout puts a compressed
+/// version where the tree already has a file, or where another one goes.
+fn place_compressed(
+    opts: &Options,
+    repo: &Repository,
+    tree: Option<&Tree>,
+    pages: &[Page],
+) -> Result<Oid> {
+    let mut files = Vec::new();
+    for page in pages {
+        let (keep_gz, keep_br) = opts.keep_compressed(&page.blobs.sizes);
+        if let (true, Some(template)) = (keep_gz, &opts.layout_gz) {
+            files.push((render_layout(template, &page.path), page.blobs.gz));
+        }
+        if let (true, Some(template)) = (keep_br, &opts.layout_br) {
+            files.push((render_layout(template, &page.path), page.blobs.br));
+        }
+        if let (Some(deflate), Some(template)) = (page.blobs.deflate, &opts.layout_deflate) {
+            files.push((render_layout(template, &page.path), deflate));
+        }
+    }
+    let mut placed = BTreeSet::new();
+    for (path, _) in &files {
+        let in_tree = tree.map_or(false, |tree| tree.get_path(Path::new(path)).is_ok());
+        if in_tree || !placed.insert(path) {
+            let message = format!("The layout puts two files at {}, check the templates.", path);
+            return Err(git2::Error::from_str(&message));
+        }
+    }
+    match tree {
+        Some(tree) if files.is_empty() => Ok(tree.id()),
+        _ => insert_paths(repo, tree, &files),
+    }
+}
+
+/// Return the path of `fname` relative to the target directory, if it is in it.
+fn path_in_target(opts: &Options, fname: &str) -> Option<String> {
+    let cwd = std::env::current_dir().ok()?;
+    let path = cwd.join(fname);
+    let relative = path.strip_prefix(cwd.join(opts.target_path.as_ref()?)).ok()?;
+    match relative.to_str() {
+        Some("") | None => None,
+        Some(relative) => Some(relative.to_string()),
+    }
+}
+
+/// Write reports to their files, or to the tree if they are inside the target.
+///
+/// Checkout would delete files in the target directory that are not in the
+/// tree. Like pages, reports in the tree get Gzip and Brotli versions where the
+/// layout templates put them. Returns the oid of the new tree.
+fn write_reports(
+    opts: &Options,
+    repo: &Repository,
+    tree: Oid,
+    reports: &[(&String, Vec<u8>)],
+) -> Result<Oid> {
+    let mut files = Vec::new();
+    for (fname, content) in reports {
+        let path = match path_in_target(opts, fname) {
+            Some(path) => path,
+            None => {
+                fs::write(fname, content)
+                    .unwrap_or_else(|err| panic!("Failed to write {}: {}", fname, err));
+                println!("Wrote {}.", fname);
+                continue;
+            }
+        };
+        println!("Adding {} to the tree.", path);
+        let gz = Gzip { iterations: zopfli_iterations(opts, content.len()) }.compress(content);
+        let brotli = Brotli { level: opts.brotli_level(fname), mode: opts.brotli_mode(fname) };
+        let br = brotli.compress(content);
+        let sizes = Sizes {
+            original_len: content.len(),
+            minified_len: content.len(),
+            gz_len: gz.len(),
+            br_len: br.len(),
+            deflate_len: 0,
+        };
+        let (keep_gz, keep_br) = opts.keep_compressed(&sizes);
+        if let (true, Some(template)) = (keep_gz, &opts.layout_gz) {
+            files.push((render_layout(template, &path), repo.blob(&gz)?));
+        }
+        if let (true, Some(template)) = (keep_br, &opts.layout_br) {
+            files.push((render_layout(template, &path), repo.blob(&br)?));
+        }
+        files.push((path, repo.blob(content)?));
+    }
+    if files.is_empty() {
+        return Ok(tree);
+    }
+    let tree = repo.find_tree(tree)?;
+    insert_paths(repo, Some(&tree), &files)
+}
+
+/// Return the files that we write, that are in the target directory but not in
+/// the tree, with their path relative to the target directory.
+///
+/// Reports are part of the tree, but the cache, the metrics, and the log are
+/// not, they change on every run.
+fn sidecar_files(opts: &Options) -> Vec<(&String, String)> {
+    [Some(&opts.cache_path), opts.metrics_path.as_ref(), opts.log_jsonl.as_ref()]
+        .into_iter()
+        .flatten()
+        .filter_map(|fname| path_in_target(opts, fname).map(|path| (fname, path)))
+        .collect()
+}
+
+/// Why we drop a file that matches none of the extension lists.
+const UNRECOGNIZED: &str = "no rule for its extension";
+
+/// The result of minimizing a tree.
+struct MinimizedTree {
+    /// Oid of the new tree, or `None` if it would be empty.
+    oid: Option<Oid>,
+
+    /// The pages in the tree, in tree traversal order.
+    pages: Vec<Page>,
+
+    /// Paths of the entries that we left out of the tree, and why.
+    dropped: Vec<(String, &'static str)>,
+
+    /// How many files we kept, per file type and what we did with them.
+    counts: BTreeMap<(String, &'static str), usize>,
+
+    /// Total size of the files we copied as-is, with `--count-assets`.
+    asset_bytes: usize,
+}
+
+/// Return the category of a file for the summary, its extension in caps.
+fn file_category(kind: &str) -> String {
+    match kind.rsplit_once('.') {
+        Some((_, ext)) if !ext.is_empty() => ext.to_uppercase(),
+        _ => "OTHER".to_string(),
+    }
+}
+
+/// Print how many files of each type we processed, the most common first.
+///
+/// This makes it easy to spot when a whole category went missing, for example
+/// because an extension rule is missing and the files got dropped.
+fn print_counts(counts: &BTreeMap<(String, &'static str), usize>, num_dropped: usize) {
+    let mut counts: Vec<_> = counts.iter().collect();
+    counts.sort_by(|(ka, na), (kb, nb)| nb.cmp(na).then(ka.cmp(kb)));
+    let mut parts: Vec<String> = counts
+        .iter()
+        .map(|((category, action), n)| format!("{}: {} ({})", category, n, action))
+        .collect();
+    parts.push(format!("dropped: {}", num_dropped));
+    println!("Files: {}", parts.join(", "));
+}
+
+/// A part of a tree that we minimized, or that another thread is minimizing.
+enum Minimized<'scope> {
+    Page(Page),
+    Subtree(String, MinimizedTree),
+    Spawned(String, thread::ScopedJoinHandle<'scope, Result<MinimizedTree>>),
+}
+
+/// Given a Git tree, make a copy where all html files are compressed.
+///
+/// This minifies .html files, and adds a Gzip and Brotli compressed version as
+/// well. Non-interesting files are dropped from the tree. `path` is the path of
+/// `tree` relative to `root`, with a trailing slash, or empty for the root.
+///
+/// Subtrees are minimized on a new thread when the walk has a spare one. The
+/// output does not depend on which subtrees that happens for.
+fn minimize_tree(
+    walk: &Walk,
+    repo: &Repository,
+    root: &Tree,
+    tree: &Tree,
+    path: &str,
+) -> Result<MinimizedTree> {
+    let opts = walk.opts;
+    let base_tree = None;
+    let mut builder = repo.treebuilder(base_tree)?;
+
+    let filemode_directory = 0o040000;
+    let filemode_regular = 0o0100644;
+
+    thread::scope(|scope| {
+        let mut parts = Vec::new();
+        let mut dropped = Vec::new();
+        let mut counts = BTreeMap::new();
+        let mut asset_bytes = 0;
+
+        for entry in tree.iter() {
+            let name = entry.name().expect("Invalid name in tree entry.");
+
+            match entry.kind() {
+                Some(ObjectType::Tree) => {
+                    // Skip directories such as the theme, that MkDocs includes
+                    // but that should not be deployed.
+                    if path.is_empty() && opts.skip_dirs.iter().any(|dir| dir == name) {
+                        dropped.push((format!("{name}/"), "in --skip-dirs"));
+                        continue;
+                    }
+                    // The config for Minimizer is not part of the site.
+                    if path.is_empty() && opts.config_from_repo && name == REPO_CONFIG_DIR {
+                        dropped.push((format!("{name}/"), "config for --config-from-repo"));
+                        continue;
+                    }
+
+                    let sub_path = format!("{path}{name}/");
+
+                    if walk.claim_thread() {
+                        let sub_id = entry.id();
+                        let handle = scope.spawn(move || {
+                            // Tree builders and repositories can't be shared
+                            // across threads, so this thread opens its own.
+                            let result = Repository::open(walk.repo_path).and_then(|repo| {
+                                let root = repo.find_tree(walk.root)?;
+                                let subtree = repo.find_tree(sub_id)?;
+                                minimize_tree(walk, &repo, &root, &subtree, &sub_path)
+                            });
+                            walk.release_thread();
+                            result
+                        });
+                        parts.push(Minimized::Spawned(name.to_string(), handle));
+                    } else {
+                        let subtree = repo.find_tree(entry.id())?;
+                        let result = minimize_tree(walk, repo, root, &subtree, &sub_path)?;
+                        parts.push(Minimized::Subtree(name.to_string(), result));
+                    }
+                }
+                Some(ObjectType::Blob) => {
+                    let file_path = format!("{path}{name}");
+                    if opts.drop_source_maps && name.ends_with(".map") {
+                        dropped.push((file_path, "a source map, with --drop-source-maps"));
+                        continue;
+                    }
+                    let mut kind = opts.classify_name(&file_path);
+                    let sniffed = match opts.sniff_content {
+                        true => sniff(repo.find_blob(entry.id())?.content()),
+                        false => None,
+                    };
+                    // Pages that we only compress can be binary, such as wasm.
+                    let is_minified = opts.is_page(&kind)
+                        && !opts.is_compress_only(&kind)
+                        && !opts.is_no_minify(&file_path);
+                    let (is_page, is_passthrough) = match sniffed {
+                        // A name that is a page already, such as xhtml, says
+                        // better how to minify it than a doctype does.
+                        Some(Sniffed::Html) if !opts.is_page(&kind) => {
+                            kind = Cow::Owned(format!("{}.html", kind));
+                            (opts.is_page(&kind), opts.is_passthrough(&kind))
+                        }
+                        Some(Sniffed::Image) => (false, true),
+                        Some(Sniffed::Binary) if is_minified => {
+                            dropped.push((file_path, "binary content in a page"));
+                            continue;
+                        }
+                        _ => (opts.is_page(&kind), opts.is_passthrough(&kind)),
+                    };
+                    let category = file_category(&kind);
+                    if is_page {
+                        if !walk.claim_page() {
+                            dropped.push((file_path, "beyond --limit"));
+                            continue;
+                        }
+                        let action = match opts.is_compress_only(&kind)
+                            || opts.is_no_minify(&file_path)
+                        {
+                            true => "compressed",
+                            false => "minified",
+                        };
+                        *counts.entry((category, action)).or_insert(0) += 1;
+                        let page = minimize_page(walk, repo, root, &entry, path, &kind)?;
+                        let blobs = page.blobs;
+                        if opts.keep_plain(&blobs.sizes) {
+                            builder.insert(name, blobs.minified, filemode_regular)?;
+                        }
+                        // The compressed versions can go elsewhere in the
+                        // tree, we add them in `place_compressed` afterwards.
+                        parts.push(Minimized::Page(page));
+                    } else if (is_passthrough || opts.no_drop)
+                        && is_too_large(opts, repo, entry.id())?
+                    {
+                        println!("Skipping large file: {}", file_path);
+                        dropped.push((file_path, "larger than --max-asset-bytes"));
+                    } else if is_passthrough {
+                        builder.insert(name, entry.id(), filemode_regular)?;
+                        *counts.entry((category, "passthrough")).or_insert(0) += 1;
+                        if opts.count_assets {
+                            asset_bytes += blob_size(repo, entry.id())?;
+                        }
+                    } else if opts.no_drop {
+                        // Files that no rule applies to, we copy as-is, including
+                        // the mode, so executables and symlinks stay what they are.
+                        builder.insert(name, entry.id(), entry.filemode())?;
+                        *counts.entry((category, "copied")).or_insert(0) += 1;
+                        if opts.count_assets {
+                            asset_bytes += blob_size(repo, entry.id())?;
+                        }
+                    } else {
+                        dropped.push((file_path, UNRECOGNIZED));
+                    }
+                }
+                ot => panic!("Unexpected object type in tree: {:?}", ot),
+            }
+        }
+
+        // Collect the results in tree order, to keep the list of pages
+        // deterministic regardless of which subtree finished first.
+        let mut pages = Vec::new();
+        for part in parts {
+            let (name, subtree) = match part {
+                Minimized::Page(page) => {
+                    pages.push(page);
+                    continue;
+                }
+                Minimized::Subtree(name, subtree) => (name, subtree),
+                Minimized::Spawned(name, handle) => {
+                    let result = handle.join().unwrap_or_else(|err| std::panic::resume_unwind(err));
+                    (name, result?)
+                }
+            };
+            if let Some(sub_oid) = subtree.oid {
+                builder.insert(name, sub_oid, filemode_directory)?;
+            }
+            pages.extend(subtree.pages);
+            dropped.extend(subtree.dropped);
+            for (key, n) in subtree.counts {
+                *counts.entry(key).or_insert(0) += n;
+            }
+            asset_bytes += subtree.asset_bytes;
+        }
+
+        let oid = if builder.is_empty() {
+            None
+        } else {
+            Some(builder.write()?)
+        };
+
+        Ok(MinimizedTree { oid, pages, dropped, counts, asset_bytes })
+    })
+}
+
+/// Check that the paths from `--require` are in the minimized tree.
+fn check_required(opts: &Options, tree: &Tree) -> Result<()> {
+    let missing: Vec<&String> = opts
+        .require
+        .iter()
+        .filter(|path| tree.get_path(Path::new(path)).is_err())
+        .collect();
+    for path in &missing {
+        println!("Required file missing from the minimized tree: {}", path);
+    }
+    if !missing.is_empty() {
+        let message = "Check the excludes and extension lists, or drop the --require.";
+        return Err(git2::Error::from_str(message));
+    }
+    Ok(())
+}
+
+/// Resolve the branch, or the revision if we got `--rev`, to an object.
+///
+/// When no branch was given, and the default one does not exist, we minimize
+/// HEAD instead. CI often has a detached checkout of the site, without branch.
+/// A branch that was given must exist, also when it is the default one.
+/// Also returns a description of where the object came from.
+fn resolve_source<'r>(
+    opts: &Options,
+    repo: &'r Repository,
+    branch: &str,
+) -> Result<(String, Object<'r>)> {
+    if opts.rev.is_some() {
+        return Ok((format!("Revision {}", branch), repo.revparse_single(branch)?));
+    }
+    match repo.find_branch(branch, BranchType::Local) {
+        Ok(pages_branch) => {
+            let commit = pages_branch.get().peel(ObjectType::Commit)?;
+            Ok((format!("Branch {}", branch), commit))
+        }
+        Err(err)
+            if err.code() == git2::ErrorCode::NotFound && !opts.branches_given =>
+        {
+            let commit = repo.head()?.peel(ObjectType::Commit)?;
+            Ok((format!("No branch {}, using HEAD", branch), commit))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Read the config and banner from the source tree, see `--config-from-repo`.
+///
+/// With multiple branches, they come from the first one.
+fn read_repo_config(opts: &Options, repo: &Repository) -> Result<RepoConfig> {
+    let source = &opts.sources()[0];
+    let tree = resolve_source(opts, repo, source)?.1.peel_to_tree()?;
+    let read = |name: &str| -> Result<Option<String>> {
+        let path = format!("{}/{}", REPO_CONFIG_DIR, name);
+        let entry = match tree.get_path(Path::new(&path)) {
+            Ok(entry) => entry,
+            Err(err) if err.code() == git2::ErrorCode::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let blob = repo.find_blob(entry.id())?;
+        let content = String::from_utf8(blob.content().to_vec()).map_err(|_| {
+            git2::Error::from_str(&format!("{} in {} is not valid UTF-8.", path, source))
+        })?;
+        println!("Using {} from {}.", path, source);
+        Ok(Some(content))
+    };
+    Ok(RepoConfig {
+        config: read("config.toml")?,
+        banner: read("banner.html")?,
+    })
+}
+
+/// Return the source tree of every branch, to detect when one moves.
+fn source_trees(opts: &Options, repo: &Repository) -> Result<Vec<Oid>> {
+    opts.sources()
+        .iter()
+        .map(|branch| resolve_source(opts, repo, branch)?.1.peel_to_tree().map(|t| t.id()))
+        .collect()
+}
+
+/// Find the tree of the branch, or of the revision if we got `--rev`.
+fn find_source_tree<'r>(opts: &Options, repo: &'r Repository, branch: &str) -> Result<Tree<'r>> {
+    let (source, object) = resolve_source(opts, repo, branch)?;
+    println!("{} -> {:?}", source, object.id());
+    object.peel_to_tree()
+}
+
+/// Minimize the tree of one branch, return the new tree and its pages.
+fn minimize_branch(
+    opts: &Options,
+    cache: &mut Cache,
+    repo: &Repository,
+    branch: &str,
+    on_page: &mut OnPage,
+    log: Option<&Mutex<fs::File>>,
+) -> Result<MinimizedTree> {
+    let mut tree = find_source_tree(opts, repo, branch)?;
+
+    // The site may be in a subdirectory of the branch, then that is the root
+    // of everything that follows, including absolute links when inlining.
+    if let Some(subdir) = &opts.subdir {
+        let entry = tree.get_path(Path::new(subdir))?;
+        if entry.kind() != Some(ObjectType::Tree) {
+            let message = format!("{} in branch {} is not a directory.", subdir, branch);
+            return Err(git2::Error::from_str(&message));
+        }
+        tree = repo.find_tree(entry.id())?;
+        println!("Subdirectory {} -> {:?}", subdir, tree.id());
+    }
+
+    let walk = Walk {
+        opts,
+        cache: Mutex::new(cache),
+        repo_path: repo.path(),
+        root: tree.id(),
+        spare_threads: AtomicUsize::new(opts.threads_walk - 1),
+        pages_left: AtomicUsize::new(opts.limit.unwrap_or(usize::MAX)),
+        compress_slots: Mutex::new(opts.threads_compress),
+        compress_done: Condvar::new(),
+        on_page: Mutex::new(on_page),
+        log: log.map(|log| (branch, log)),
+    };
+    let root_path = "";
+    let mut result = minimize_tree(&walk, repo, &tree, &tree, root_path)?;
+    if opts.list_dropped {
+        for (path, reason) in &result.dropped {
+            println!("Dropped {}: {}", path, reason);
+        }
+    }
+
+    // Losing files silently is worse than failing, unless we are asked to.
+    // Return an error rather than exiting, so the cache gets saved.
+    let unrecognized: Vec<&String> = result
+        .dropped
+        .iter()
+        .filter(|(_, reason)| *reason == UNRECOGNIZED)
+        .map(|(path, _)| path)
+        .collect();
+    if !unrecognized.is_empty() && !opts.drop_unrecognized {
+        println!("These files match none of the extension lists:");
+        for path in unrecognized {
+            println!("  {}", path);
+        }
+        let message = "Add their extensions to a list, or pass --drop-unrecognized to drop them.";
+        return Err(git2::Error::from_str(message));
+    }
+
+    if result.oid.is_none() && result.pages.is_empty() {
+        println!("No files in branch {} matched the processing rules:", branch);
+        opts.print_extensions();
+        // Return an error rather than exiting, so the cache keeps the pages of
+        // the branches that we minimized before this one.
+        let message = "Nothing to minimize, check the extension lists and --skip-dirs.";
+        return Err(git2::Error::from_str(message));
+    }
+    let misses: Vec<&Page> = result.pages.iter().filter(|page| !page.from_cache).collect();
+    if opts.list_misses {
+        for page in &misses {
+            println!("Not in cache: {}", page.path);
+        }
+    }
+    println!(
+        "Took {} of {} pages from the cache.",
+        result.pages.len() - misses.len(),
+        result.pages.len(),
+    );
+
+    let tree_min = result.oid.map(|oid| repo.find_tree(oid)).transpose()?;
+    let mut oid = place_compressed(opts, repo, tree_min.as_ref(), &result.pages)?;
+    if let Some(alias) = opts.alias_pages {
+        oid = add_page_aliases(repo, oid, alias)?;
+    }
+    result.oid = Some(oid);
+    println!("Minimized tree  -> {:?}", result.oid.unwrap());
+    check_required(opts, &repo.find_tree(oid)?)?;
+
+    print_counts(&result.counts, result.dropped.len());
+    let sizes = result.pages.iter().fold(Sizes::default(), |acc, page| acc + page.blobs.sizes);
+    println!("{}", report::Colored(&sizes));
+    if opts.count_assets {
+        let tree_bytes = tree_size(repo, &repo.find_tree(oid)?)?;
+        println!("Assets: {}, Tree: {}", result.asset_bytes, tree_bytes);
+    }
+
+    Ok(result)
+}
+
+/// Minimize the branches, return the tree to check out.
+///
+/// For a single branch that is the minimized tree of the branch. For multiple
+/// branches, the tree has a directory named after every branch.
+fn minimize(
+    opts: &Options,
+    cache: &mut Cache,
+    pages: &mut Vec<Page>,
+    repo: &Repository,
+    on_page: &mut OnPage,
+) -> Result<Oid> {
+    let log = opts.log_jsonl.as_ref().map(|fname| {
+        let file = fs::File::create(fname)
+            .unwrap_or_else(|err| panic!("Failed to create {}: {}", fname, err));
+        Mutex::new(file)
+    });
+    let tree_min = match opts.sources() {
+        [branch] => {
+            let result = minimize_branch(opts, cache, repo, branch, on_page, log.as_ref())?;
+            *pages = result.pages;
+            result.oid.expect("Must have a root tree.")
+        }
+        branches => {
+            let base_tree = None;
+            let mut builder = repo.treebuilder(base_tree)?;
+            let filemode_directory = 0o040000;
+
+            for branch in branches {
+                let result = minimize_branch(opts, cache, repo, branch, on_page, log.as_ref())?;
+                let oid = result.oid.expect("Must have a root tree.");
+                builder.insert(branch, oid, filemode_directory)?;
+                pages.extend(result.pages.into_iter().map(|page| Page {
+                    path: format!("{}/{}", branch, page.path),
+                    ..page
+                }));
+            }
+
+            let tree_min = builder.write()?;
+            println!("Combined tree   -> {:?}", tree_min);
+
+            let sizes = pages.iter().fold(Sizes::default(), |acc, page| acc + page.blobs.sizes);
+            println!("Total: {}", report::Colored(&sizes));
+            tree_min
+        }
+    };
+
+    if let Some(largest) = pages.iter().max_by_key(|page| page.blobs.sizes.br_len) {
+        println!(
+            "Largest page: {} ({} bytes Brotli)",
+            largest.path, largest.blobs.sizes.br_len,
+        );
+    }
+
+    // Pages from old cache entries have no time, they don't count here.
+    let mut by_time: Vec<&Page> = pages.iter().filter(|page| page.blobs.millis.is_some()).collect();
+    by_time.sort_by_key(|page| std::cmp::Reverse(page.blobs.millis));
+    if !by_time.is_empty() {
+        let slowest: Vec<String> = by_time
+            .iter()
+            .take(3)
+            .map(|page| format!("{} ({} ms)", page.path, page.blobs.millis.unwrap()))
+            .collect();
+        println!("Slowest pages: {}", slowest.join(", "));
+    }
+
+    // Pages from the cache don't know the size of their scripts.
+    let js_sizes: Vec<(usize, usize)> =
+        pages.iter().filter_map(|page| page.blobs.inline_js).collect();
+    if !js_sizes.is_empty() {
+        let before: usize = js_sizes.iter().map(|(before, _)| before).sum();
+        let after: usize = js_sizes.iter().map(|(_, after)| after).sum();
+        println!(
+            "Inline JavaScript in {} minified pages: {} bytes, {} after minification ({:+.1}%).",
+            js_sizes.len(),
+            before,
+            after,
+            100.0 * (after as f32 - before as f32) / before.max(1) as f32,
+        );
+    }
+
+    if opts.histogram {
+        println!("Brotli size relative to the original, by page:");
+        report::print_histogram(pages.iter().map(|page| &page.blobs.sizes));
+    }
+
+    if opts.measure_decode {
+        print_decode_times(repo, pages)?;
+    }
+
+    Ok(tree_min)
+}
+
+/// The ref that points at the tree that we last checked out.
+const LAST_TREE_REF: &str = "refs/minimizer/last";
+
+/// The ref that points at the tree that we last minimized.
+const MINIMIZED_TREE_REF: &str = "refs/minimizer/minimized";
+
+/// Print the files that differ between the last checked out tree and `root`.
+fn print_diff_tree(repo: &Repository, root: Oid) -> Result<()> {
+    let last = match repo.find_reference(LAST_TREE_REF) {
+        Ok(reference) => reference.peel_to_tree()?,
+        Err(err) if err.code() == git2::ErrorCode::NotFound => {
+            println!("No previous tree in {}, all files are new.", LAST_TREE_REF);
+            return Ok(());
+        }
+        Err(err) => return Err(err),
+    };
+    let tree = repo.find_tree(root)?;
+    let diff = repo.diff_tree_to_tree(Some(&last), Some(&tree), None)?;
+
+    for delta in diff.deltas() {
+        let (status, file) = match delta.status() {
+            git2::Delta::Added => ("A", delta.new_file()),
+            git2::Delta::Deleted => ("D", delta.old_file()),
+            _ => ("M", delta.new_file()),
+        };
+        let path = file.path().expect("Diff entries should have a path.");
+        println!("{} {}", status, path.display());
+    }
+    println!("{} files changed since {:?}.", diff.deltas().len(), last.id());
+
+    Ok(())
+}
+
+/// Print the mode, oid, size, and path of every entry in the tree at `root`.
+///
+/// This is like `git ls-tree -r -t -l`, subtrees have no size.
+fn print_tree(repo: &Repository, root: Oid) -> Result<()> {
+    let tree = repo.find_tree(root)?;
+    let mut entries = Vec::new();
+    tree.walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+        let path = format!("{}{}", dir, entry.name().expect("Invalid name in tree entry."));
+        entries.push((entry.filemode(), entry.kind(), entry.id(), path));
+        git2::TreeWalkResult::Ok
+    })?;
+
+    let odb = repo.odb()?;
+    for (mode, kind, oid, path) in entries {
+        let size = match kind {
+            Some(ObjectType::Blob) => odb.read_header(oid)?.0.to_string(),
+            _ => "-".to_string(),
+        };
+        println!("{:06o} {} {:>8} {}", mode, oid, size, path);
+    }
+
+    Ok(())
+}
+
+/// Return why a checkout into `target_dir` could destroy files, if it could.
+///
+/// Checkout removes whatever is in the directory. That is fine if we wrote it,
+/// but not for the working directory of the repository, or for a directory
+/// that has files that were not in the tree of the last checkout. `sidecars`
+/// are the paths in the directory of files that we keep through checkout.
+fn unsafe_target_reason(
+    repo: &Repository,
+    target_dir: &Path,
+    sidecars: &[String],
+) -> Result<Option<String>> {
+    let io_error = |err: io::Error| {
+        git2::Error::from_str(&format!("Failed to inspect {}: {}", target_dir.display(), err))
+    };
+
+    let mut names = match fs::read_dir(target_dir) {
+        Ok(entries) => entries
+            .map(|entry| entry.map(|e| e.file_name()))
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(io_error)?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(io_error(err)),
+    };
+
+    names.retain(|name| {
+        let name = name.to_string_lossy();
+        let dir = format!("{}/", name);
+        !sidecars.iter().any(|path| *path == name || path.starts_with(&dir))
+    });
+
+    if let Some(workdir) = repo.workdir() {
+        let target = target_dir.canonicalize().map_err(io_error)?;
+        if workdir.canonicalize().map_err(io_error)? == target {
+            return Ok(Some("it is the working directory of the repository".to_string()));
+        }
+    }
+
+    if names.is_empty() {
+        return Ok(None);
+    }
+
+    let last = match repo.find_reference(LAST_TREE_REF) {
+        Ok(reference) => reference.peel_to_tree()?,
+        Err(err) if err.code() == git2::ErrorCode::NotFound => {
+            return Ok(Some("it is not empty, and we did not check out into it before".to_string()));
+        }
+        Err(err) => return Err(err),
+    };
+
+    for name in names {
+        let name = name.to_string_lossy();
+        if last.get_name(&name).is_none() {
+            return Ok(Some(format!("it contains {}, which was not in the last checkout", name)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Check out the given tree at the given path.
+///
+/// This is a destructive function that clears whatever is currently at that
+/// path. If the path does not exist yet, it is created.
+fn checkout_into<P: AsRef<Path>>(repo: &Repository, root: Oid, target_dir: P) -> Result<()> {
+    // Git refuses to check out into a directory whose parent does not exist,
+    // and the error it reports for that is not very helpful, so create it.
+    fs::create_dir_all(target_dir.as_ref()).map_err(|err| {
+        git2::Error::from_str(&format!(
+            "Failed to create target directory {}: {}",
+            target_dir.as_ref().display(),
+            err,
+        ))
+    })?;
+
+    // Git overwrites files in place, which for a hardlink would change all of
+    // the linked files. Remove them, so checkout recreates them instead.
+    remove_hardlinks(target_dir.as_ref()).map_err(|err| {
+        git2::Error::from_str(&format!(
+            "Failed to remove hardlinks in {}: {}",
+            target_dir.as_ref().display(),
+            err,
+        ))
+    })?;
+
+    let mut checkout_builder = CheckoutBuilder::new();
+    checkout_builder
+        .target_dir(target_dir.as_ref())
+        .update_index(false)
+        .remove_ignored(true)
+        .remove_untracked(true)
+        .force();
+
+    // Report progress like for minification, on a single line. Only print
+    // when the percentage changes, a large site has many files, and only on a
+    // terminal, in a log the carriage returns would pile up on one line.
+    let mut last_pct = None;
+    let is_terminal = atty::is(atty::Stream::Stdout);
+    checkout_builder.progress(move |_path, current, total| {
+        if !is_terminal {
+            return;
+        }
+        use std::io::Write;
+        let pct = if total == 0 { 100 } else { current * 100 / total };
+        if last_pct == Some(pct) {
+            return;
+        }
+        last_pct = Some(pct);
+        let mut stdout = std::io::stdout().lock();
+        write!(stdout, "\rChecking out: {}/{} files ({}%)", current, total, pct).unwrap();
+        if current == total {
+            writeln!(stdout).unwrap();
+        }
+        stdout.flush().unwrap();
+    });
+
+    let root_obj = repo.find_object(root, Some(ObjectType::Tree))?;
+    repo.checkout_tree(&root_obj, Some(&mut checkout_builder))
+}
+
+/// Write the given tree to the given path, without Git's checkout.
+///
+/// Like [`checkout_into`], this clears whatever is at that path first, but
+/// then it writes every blob itself, it never touches the index.
+fn write_files(repo: &Repository, root: Oid, target_dir: &Path) -> Result<()> {
+    let io_error = |action: &str, path: &Path, err: io::Error| {
+        git2::Error::from_str(&format!("Failed to {} {}: {}", action, path.display(), err))
+    };
+    fs::create_dir_all(target_dir).map_err(|err| io_error("create", target_dir, err))?;
+    let entries = fs::read_dir(target_dir).map_err(|err| io_error("read", target_dir, err))?;
+    for entry in entries {
+        let path = entry.map_err(|err| io_error("read", target_dir, err))?.path();
+        let removed = match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.is_dir() => fs::remove_dir_all(&path),
+            _ => fs::remove_file(&path),
+        };
+        removed.map_err(|err| io_error("remove", &path, err))?;
+    }
+
+    let n = write_tree(repo, &repo.find_tree(root)?, target_dir)?;
+    println!("Wrote {} files.", n);
+    Ok(())
+}
+
+/// Write the blobs in the tree into `dir`, respecting their mode.
+///
+/// Returns the number of files written.
+fn write_tree(repo: &Repository, tree: &Tree, dir: &Path) -> Result<usize> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let io_error = |path: &Path, err: io::Error| {
+        git2::Error::from_str(&format!("Failed to write {}: {}", path.display(), err))
+    };
+    let filemode_executable = 0o100755;
+    let filemode_link = 0o120000;
+
+    let mut n = 0;
+    for entry in tree.iter() {
+        let path = dir.join(entry.name().expect("Invalid name in tree entry."));
+        match entry.kind() {
+            Some(ObjectType::Tree) => {
+                fs::create_dir(&path).map_err(|err| io_error(&path, err))?;
+                n += write_tree(repo, &repo.find_tree(entry.id())?, &path)?;
+            }
+            Some(ObjectType::Blob) => {
+                let blob = repo.find_blob(entry.id())?;
+                if entry.filemode() == filemode_link {
+                    let target = std::str::from_utf8(blob.content()).expect("Invalid link target.");
+                    std::os::unix::fs::symlink(target, &path).map_err(|err| io_error(&path, err))?;
+                } else {
+                    fs::write(&path, blob.content()).map_err(|err| io_error(&path, err))?;
+                }
+                if entry.filemode() == filemode_executable {
+                    let permissions = fs::Permissions::from_mode(0o755);
+                    fs::set_permissions(&path, permissions).map_err(|err| io_error(&path, err))?;
+                }
+                n += 1;
+            }
+            ot => panic!("Unexpected object type in tree: {:?}", ot),
+        }
+    }
+    Ok(n)
+}
+
+/// Check out the tree into `target_path`, unless that could destroy files.
+///
+/// Afterwards, `LAST_TREE_REF` points at the tree, so the next run can diff
+/// against it, and knows which files in the target it may remove. We only move
+/// it once the checkout succeeded, a failed one leaves the target unknown.
+fn check_out(opts: &Options, repo: &Repository, root_tree: Oid, target_path: &str) -> Result<()> {
+    let sidecars = sidecar_files(opts);
+    let sidecar_paths: Vec<String> = sidecars.iter().map(|(_, path)| path.clone()).collect();
+    let target_dir = Path::new(target_path);
+
+    if !opts.force_overwrite {
+        if let Some(reason) = unsafe_target_reason(repo, target_dir, &sidecar_paths)? {
+            println!("Refusing to check out into {}, because {}.", target_path, reason);
+            println!("Pass --force-overwrite to check out anyway.");
+            std::process::exit(1);
+        }
+    }
+
+    // Checkout deletes files that are not in the tree, so we put back the
+    // ones that we wrote ourselves afterwards.
+    let mut kept = Vec::new();
+    for (fname, path) in &sidecars {
+        if let Ok(content) = fs::read(fname) {
+            println!("Keeping {} through checkout.", path);
+            kept.push((fname, content));
+        }
+    }
 
-    checkout_into(&repo, root_tree, &target_path)?;
+    if opts.write_files {
+        write_files(repo, root_tree, target_dir)?;
+    } else {
+        checkout_into(repo, root_tree, target_path)?;
+    }
     println!("Checked out tree {:?} at {}.", root_tree, target_path);
 
+    for (fname, content) in kept {
+        let dir = Path::new(fname).parent().expect("Sidecar is in the target directory.");
+        fs::create_dir_all(dir).expect("Failed to create directory for sidecar.");
+        fs::write(fname, content)
+            .unwrap_or_else(|err| panic!("Failed to write {}: {}", fname, err));
+    }
+
+    let log_message = "minimizer: check out minimized tree";
+    repo.reference(LAST_TREE_REF, root_tree, true, log_message)?;
+
+    if opts.hardlink_duplicates {
+        let n = hardlink_duplicates(repo, root_tree, target_dir)?;
+        println!("Replaced {} duplicate files with hardlinks.", n);
+    }
+
+    Ok(())
+}
+
+/// Recursively remove all files in the directory that have more than one link.
+fn remove_hardlinks(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            remove_hardlinks(&entry.path())?;
+        } else if file_type.is_file() && entry.metadata()?.nlink() > 1 {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Replace files in a checkout of the tree that have the same blob by hardlinks.
+///
+/// After checkout, every file is a separate copy on disk, even when the blobs
+/// are identical, such as for pages that are the same. This links all files
+/// with the same blob to the first one. Returns the number of replaced files.
+fn hardlink_duplicates(repo: &Repository, root: Oid, target_dir: &Path) -> Result<usize> {
+    use std::collections::btree_map::Entry;
+
+    // Symlinks are checked out as links, and linking an executable to a
+    // regular file would change its mode, so only link blobs with equal mode.
+    let filemode_link = 0o120000;
+    let tree = repo.find_tree(root)?;
+    let mut first_paths: BTreeMap<(Oid, i32), String> = BTreeMap::new();
+    let mut duplicates = Vec::new();
+
+    tree.walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+        if entry.kind() == Some(ObjectType::Blob) && entry.filemode() != filemode_link {
+            let path = format!("{}{}", dir, entry.name().expect("Invalid name in tree entry."));
+            match first_paths.entry((entry.id(), entry.filemode())) {
+                Entry::Vacant(v) => {
+                    v.insert(path);
+                }
+                Entry::Occupied(o) => duplicates.push((o.get().clone(), path)),
+            }
+        }
+        git2::TreeWalkResult::Ok
+    })?;
+
+    for (original, duplicate) in duplicates.iter() {
+        let duplicate_path = target_dir.join(duplicate);
+        fs::remove_file(&duplicate_path)
+            .and_then(|_| fs::hard_link(target_dir.join(original), &duplicate_path))
+            .map_err(|err| {
+                git2::Error::from_str(&format!(
+                    "Failed to link {} to {}: {}",
+                    duplicate_path.display(),
+                    original,
+                    err,
+                ))
+            })?;
+    }
+
+    Ok(duplicates.len())
+}
+
+/// Commit the minimized tree on top of the source branch, and advance the branch.
+///
+/// This fails if the branch moved since we read `source_tree` from it, so we
+/// never commit on top of a commit that the tree was not minimized from.
+fn commit_to_branch(opts: &Options, repo: &Repository, source_tree: Oid, root: Oid) -> Result<()> {
+    let branch = &opts.branches[0];
+    let reference = repo.find_branch(branch, BranchType::Local)?.into_reference();
+    let parent = reference.peel_to_commit()?;
+    if parent.tree_id() != source_tree {
+        let message = format!("Branch {} moved while minimizing, not committing.", branch);
+        return Err(git2::Error::from_str(&message));
+    }
+    if parent.tree_id() == root {
+        println!("Branch {} is minimized already.", branch);
+        return Ok(());
+    }
+
+    let signature = repo
+        .signature()
+        .or_else(|_| git2::Signature::now("Minimizer", "minimizer@localhost"))?;
+    let message = format!("Minimize {}\n\nMinimized from {}.\n", branch, parent.id());
+    let tree = repo.find_tree(root)?;
+    let commit = repo.commit(None, &signature, &signature, &message, &tree, &[&parent])?;
+
+    let ref_name = reference.name().expect("Branch name should be UTF-8.");
+    let log_message = "minimizer: commit minimized tree";
+    repo.reference_matching(ref_name, commit, true, parent.id(), log_message)?;
+    println!("Committed {:?} on branch {}.", commit, branch);
+
+    Ok(())
+}
+
+/// Print how many pages we processed in how much time, including cache hits.
+fn print_throughput(pages: &[Page], start: std::time::Instant) {
+    let bytes: usize = pages.iter().map(|page| page.blobs.sizes.original_len).sum();
+    let secs = start.elapsed().as_secs_f64();
+    println!(
+        "Processed {} pages ({:.1} MB) in {:.1}s, {:.0} KB/s.",
+        pages.len(),
+        bytes as f64 / 1e6,
+        secs,
+        bytes as f64 / 1e3 / secs.max(1e-3),
+    );
+}
+
+/// Write the cache to a new file, then move it into place, so a crash while
+/// writing leaves the old cache intact.
+///
+/// With `--cache-append`, only the entries that differ from `saved`, the ones
+/// that we loaded, go to the end of the existing file, if it can take them.
+fn save_cache(opts: &Options, cache: &Cache, saved: &BTreeMap<Oid, MinifiedBlobs>) {
+    if opts.cache_append && cache.append(&opts.cache_path, saved).expect("Failed to save cache.") {
+        return;
+    }
+    let cache_path_new = format!("{}.new", opts.cache_path);
+    let gzip = opts.cache_path.ends_with(".gz");
+    cache.save(&cache_path_new, gzip).expect("Failed to save cache.");
+    fs::rename(&cache_path_new, &opts.cache_path).expect("Failed to move cache.");
+}
+
+fn main() -> Result<()> {
+    let start = std::time::Instant::now();
+    // Skip the program name.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let mut opts = Options::parse(args.iter().cloned());
+    if opts.init {
+        config::init_config(opts.force);
+        return Ok(());
+    }
+    if opts.cache_list {
+        list_cache(&opts);
+        return Ok(());
+    }
+    if let Some(fname) = &opts.file {
+        minimize_single_file(&opts, fname);
+        return Ok(());
+    }
+    if opts.output_s3.is_some() && !cfg!(feature = "s3") {
+        println!("--output-s3 needs Minimizer built with the s3 feature.");
+        std::process::exit(1);
+    }
+    let repo = match Repository::open(&opts.repo_path) {
+        Ok(repo) => repo,
+        Err(err) if err.code() == git2::ErrorCode::NotFound => {
+            println!("Not a Git repository: {}", opts.repo_path);
+            std::process::exit(1);
+        }
+        Err(err) => return Err(err),
+    };
+
+    // Find out now, rather than after minimizing a large site.
+    if let Some(target_path) = &opts.target_path {
+        if Path::new(target_path).exists() && !Path::new(target_path).is_dir() {
+            println!("Not a directory: {}", target_path);
+            std::process::exit(1);
+        }
+    }
+
+    if let (Some(rev), Some(target_path)) = (&opts.checkout, &opts.target_path) {
+        let root_tree = repo.revparse_single(rev)?.peel_to_tree()?.id();
+        return check_out(&opts, &repo, root_tree, target_path);
+    }
+
+    if opts.config_from_repo {
+        let repo_config = read_repo_config(&opts, &repo)?;
+        opts = Options::parse_with(args.into_iter(), repo_config);
+    }
+
+    // Keep new objects in memory, so nothing gets written to the repository.
+    // The backend with the highest priority is the one that takes writes.
+    if opts.stats_only {
+        repo.odb()?.add_new_mempack_backend(1000)?;
+    }
+
+    let mut cache = match Cache::load(&opts.cache_path) {
+        Ok(cache) => cache,
+        Err(_) => {
+            println!("Starting with empty cache, cache failed to load.");
+            Cache::new()
+        }
+    };
+    let loaded = match opts.cache_append {
+        true => cache.0.clone(),
+        false => BTreeMap::new(),
+    };
+
+    if opts.verify_cache {
+        let n = cache.remove_missing(&repo)?;
+        println!("Dropped {} cache entries with objects missing from the repository.", n);
+    }
+
+    // The page was on the status line while we minimized it, finish that line.
+    let mut print_page = |path: &Path, sizes: &Sizes| {
+        println!("\r{}: {}", path.display(), report::Colored(sizes));
+    };
+
+    let mut pages = Vec::new();
+    let mut sources;
+    let root_tree = loop {
+        sources = source_trees(&opts, &repo)?;
+        pages.clear();
+
+        let result = if opts.reproducible {
+            // Start from an empty cache, so entries from a different version or
+            // with different options can't end up in the output. Then the entries
+            // that were in the cache should match what we produced now.
+            let mut fresh = Cache::new();
+            let result = minimize(&opts, &mut fresh, &mut pages, &repo, &mut print_page);
+            if result.is_ok() {
+                cache.check_reproduced(&fresh);
+                println!("Output matches the cache.");
+            }
+            cache.0.extend(fresh.0);
+            result
+        } else {
+            minimize(&opts, &mut cache, &mut pages, &repo, &mut print_page)
+        };
+
+        // On a large site, a failure near the end should not throw away all
+        // the pages that we did minify, the next run can reuse them.
+        let root_tree = match result {
+            Ok(root_tree) => root_tree,
+            Err(err) if opts.stats_only => return Err(err),
+            Err(err) => {
+                println!("Saving the cache with the pages minimized so far.");
+                save_cache(&opts, &cache, &loaded);
+                return Err(err);
+            }
+        };
+
+        // On a large site, a push may have come in while we were minimizing.
+        let action = match opts.on_source_moved {
+            Some(action) if source_trees(&opts, &repo)? != sources => action,
+            _ => break root_tree,
+        };
+        match action {
+            SourceMoved::Warn => {
+                println!("Warning: the source moved while minimizing, the result is outdated.");
+                break root_tree;
+            }
+            SourceMoved::Fail => {
+                println!("The source moved while minimizing, not checking out.");
+                // The pages that did not change are still good for the next run.
+                if !opts.stats_only {
+                    save_cache(&opts, &cache, &loaded);
+                }
+                std::process::exit(1);
+            }
+            SourceMoved::Restart => {
+                // The cache still has the pages that did not change.
+                println!("The source moved while minimizing, starting over.");
+            }
+        }
+    };
+
+    if opts.stats_only {
+        // The cache would refer to blobs that only existed in memory.
+        println!("Stats only, not saving the cache or checking out.");
+        print_throughput(&pages, start);
+        return Ok(());
+    }
+
+    if let Some(days) = opts.max_cache_age_days {
+        let n = cache.remove_unused(days);
+        println!("Dropped {} cache entries that were not used in {} days.", n, days);
+    }
+    save_cache(&opts, &cache, &loaded);
+
+    let mut reports = Vec::new();
+    if let Some(manifest_path) = &opts.manifest_path {
+        let mut manifest = Vec::new();
+        write_manifest(&pages, &mut manifest).expect("Failed to write manifest.");
+        reports.push((manifest_path, manifest));
+    }
+    if let Some(badge_path) = &opts.badge_path {
+        let sizes = pages.iter().fold(Sizes::default(), |acc, page| acc + page.blobs.sizes);
+        reports.push((badge_path, report::badge(&sizes).into_bytes()));
+    }
+    let root_tree = write_reports(&opts, &repo, root_tree, &reports)?;
+
+    if let Some(dump_dir) = &opts.dump_decompressed_dir {
+        dump_decompressed(&repo, &pages, Path::new(dump_dir))?;
+        println!("Wrote decompressed pages to {}.", dump_dir);
+    }
+
+    if opts.print_tree {
+        print_tree(&repo, root_tree)?;
+    }
+
+    if opts.diff_tree {
+        print_diff_tree(&repo, root_tree)?;
+    }
+
+    if opts.fail_on_growth && check_growth(&pages) {
+        println!("Minified site is larger than the original, not checking out.");
+        std::process::exit(1);
+    }
+
+    if opts.fail_on_budget && pages.iter().any(|page| opts.exceeds_budget(&page.blobs.sizes)) {
+        println!("Some pages exceed the budget, not checking out.");
+        std::process::exit(1);
+    }
+
+    if opts.dry_run {
+        println!("Dry run, not checking out tree {:?}.", root_tree);
+        print_throughput(&pages, start);
+        return Ok(());
+    }
+
+    // Point a ref at the tree, so Git does not garbage collect the minified
+    // blobs before they are checked out.
+    let log_message = "minimizer: minimize tree";
+    repo.reference(MINIMIZED_TREE_REF, root_tree, true, log_message)?;
+
+    if opts.commit_to_branch {
+        commit_to_branch(&opts, &repo, sources[0], root_tree)?;
+    }
+
+    #[cfg(feature = "s3")]
+    if let Some(url) = &opts.output_s3 {
+        s3::upload_tree(&opts, &repo, root_tree, &pages, &reports, url)?;
+    }
+
+    match &opts.target_path {
+        Some(target_path) => check_out(&opts, &repo, root_tree, target_path)?,
+        None if opts.output_s3.is_some() => {}
+        None => {
+            println!("No target directory given, not checking out tree {:?}.", root_tree);
+            println!(
+                "Check it out with: minimizer checkout {} {} <target>",
+                opts.repo_path, MINIMIZED_TREE_REF,
+            );
+        }
+    }
+
+    if let Some(metrics_path) = &opts.metrics_path {
+        let sizes = pages.iter().fold(Sizes::default(), |acc, page| acc + page.blobs.sizes);
+        let metrics = report::Metrics {
+            sizes,
+            pages: pages.len(),
+            cache_hits: pages.iter().filter(|page| page.from_cache).count(),
+            duration: start.elapsed(),
+        };
+        let metrics_path_new = format!("{}.new", metrics_path);
+        metrics.save(&metrics_path_new).expect("Failed to write metrics.");
+        fs::rename(&metrics_path_new, metrics_path).expect("Failed to move metrics.");
+    }
+
+    print_throughput(&pages, start);
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Return a path in the temporary directory that is unique to the test.
+    fn temp_path(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("minimizer-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(name).to_str().unwrap().to_string()
+    }
+
+    /// Return an oid that is all `n`, so tests can tell them apart.
+    fn oid(n: u8) -> Oid {
+        Oid::from_bytes(&[n; 20]).unwrap()
+    }
+
+    /// Return a cache entry with every optional column filled in.
+    fn entry(n: u8) -> MinifiedBlobs {
+        MinifiedBlobs {
+            minified: oid(n),
+            gz: oid(n + 1),
+            br: oid(n + 2),
+            deflate: Some(oid(n + 3)),
+            sizes: Sizes {
+                original_len: 1000 + n as usize,
+                minified_len: 800,
+                gz_len: 300,
+                br_len: 250,
+                deflate_len: 280,
+            },
+            millis: Some(42),
+            config: Some(oid(n + 4)),
+            used: Some(1_700_000_000),
+            inline_js: None,
+        }
+    }
+
+    /// Return the cache as a document, for comparing caches.
+    fn to_tsv(cache: &Cache) -> String {
+        let mut out = Vec::new();
+        cache.serialize(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// Return a cache with entries with and without the optional columns.
+    fn example_cache() -> Cache {
+        let mut cache = Cache::new();
+        cache.0.insert(oid(1), entry(10));
+        cache.0.insert(oid(2), entry(20));
+        cache.0.insert(oid(3), MinifiedBlobs { deflate: None, millis: None, ..entry(30) });
+        cache
+    }
+
+    #[test]
+    fn cache_round_trips_plain_and_gzipped() {
+        let cache = example_cache();
+        for (name, gzip) in [("plain.tsv", false), ("gzipped.tsv.gz", true)] {
+            let fname = temp_path(name);
+            cache.save(&fname, gzip).unwrap();
+            let is_gzip = fs::read(&fname).unwrap().starts_with(&[0x1f, 0x8b]);
+            assert_eq!(is_gzip, gzip, "{}", name);
+            let loaded = Cache::load(&fname).unwrap();
+            assert_eq!(to_tsv(&loaded), to_tsv(&cache), "{}", name);
+        }
+    }
+
+    #[test]
+    fn cache_loads_every_header_version() {
+        let mut latest = Vec::new();
+        Cache::serialize_row(&mut latest, &oid(1), &entry(10)).unwrap();
+        let latest = String::from_utf8(latest).unwrap();
+        for (version, header) in Cache::HEADERS.iter().enumerate() {
+            // Older versions have a prefix of the columns of the latest one.
+            let n_columns = header.split('\t').count();
+            let row: Vec<&str> = latest.trim_end().split('\t').take(n_columns).collect();
+            let fname = temp_path(&format!("version-{}.tsv", version));
+            fs::write(&fname, format!("{}\n{}\n", header, row.join("\t"))).unwrap();
+
+            let loaded = Cache::load(&fname).unwrap();
+            let v = loaded.0[&oid(1)];
+            assert_eq!((v.minified, v.gz, v.br), (oid(10), oid(11), oid(12)));
+            assert_eq!(v.sizes.original_len, 1010);
+            assert_eq!(v.sizes.br_len, 250);
+            assert_eq!(v.deflate.is_some(), version >= 1, "version {}", version);
+            assert_eq!(v.sizes.deflate_len, if version >= 1 { 280 } else { 0 });
+            assert_eq!(v.millis.is_some(), version >= 2, "version {}", version);
+            assert_eq!(v.config.is_some(), version >= 3, "version {}", version);
+            assert_eq!(v.used.is_some(), version >= 4, "version {}", version);
+        }
+    }
+
+    #[test]
+    fn cache_append_adds_changed_entries() {
+        let fname = temp_path("append.tsv");
+        let mut cache = example_cache();
+        cache.save(&fname, false).unwrap();
+        let saved = cache.0.clone();
+
+        // A new entry and a changed one get appended, a new use time alone does not.
+        cache.0.insert(oid(4), entry(40));
+        cache.0.insert(oid(1), entry(50));
+        cache.0.get_mut(&oid(2)).unwrap().used = Some(1_800_000_000);
+        assert!(cache.append(&fname, &saved).unwrap());
+        let n_rows = fs::read_to_string(&fname).unwrap().lines().count();
+        assert_eq!(n_rows, 1 + 3 + 2);
+
+        let loaded = Cache::load(&fname).unwrap();
+        assert_eq!(loaded.0.len(), 4);
+        assert_eq!(loaded.0[&oid(1)].minified, oid(50));
+        assert_eq!(loaded.0[&oid(4)].minified, oid(40));
+        assert_eq!(loaded.0[&oid(2)].used, Some(1_700_000_000));
+    }
+
+    #[test]
+    fn cache_append_needs_a_full_save_for_other_files() {
+        let cache = example_cache();
+        let missing = temp_path("append-missing.tsv");
+        assert!(!cache.append(&missing, &BTreeMap::new()).unwrap());
+        assert!(!Path::new(&missing).exists());
+
+        let gzipped = temp_path("append-gzipped.tsv");
+        cache.save(&gzipped, true).unwrap();
+        assert!(!cache.append(&gzipped, &BTreeMap::new()).unwrap());
+
+        let older = temp_path("append-older.tsv");
+        fs::write(&older, format!("{}\n", Cache::HEADERS[0])).unwrap();
+        assert!(!cache.append(&older, &BTreeMap::new()).unwrap());
+    }
+
+    #[test]
+    fn cache_ignores_a_truncated_last_row() {
+        let fname = temp_path("truncated.tsv");
+        let cache = example_cache();
+        cache.save(&fname, false).unwrap();
+
+        // A crash while appending leaves part of a row, without its newline.
+        let mut row = Vec::new();
+        Cache::serialize_row(&mut row, &oid(4), &entry(40)).unwrap();
+        let mut content = fs::read(&fname).unwrap();
+        content.extend_from_slice(&row[..row.len() / 2]);
+        fs::write(&fname, content).unwrap();
+
+        let loaded = Cache::load(&fname).unwrap();
+        assert_eq!(to_tsv(&loaded), to_tsv(&cache));
+        // Appending after the cut-off row would corrupt the next row as well.
+        assert!(!loaded.append(&fname, &BTreeMap::new()).unwrap());
+    }
+
+    #[test]
+    fn render_layout_fills_in_the_placeholders() {
+        let template = "{path}|{dir}|{name}|{stem}|{ext}";
+        let cases = [
+            ("index.html", "index.html||index.html|index|.html"),
+            ("docs/api/page.html", "docs/api/page.html|docs/api/|page.html|page|.html"),
+            ("about", "about||about|about|"),
+            ("docs/about", "docs/about|docs/|about|about|"),
+            ("js/app.min.js", "js/app.min.js|js/|app.min.js|app.min|.js"),
+            (".htaccess", ".htaccess||.htaccess|.htaccess|"),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(render_layout(template, path), expected, "{}", path);
+        }
+        assert_eq!(render_layout("{dir}{stem}.gz{ext}", "a/b.tar.xz"), "a/b.tar.gz.xz");
+        assert_eq!(render_layout("gz/{path}", "index.html"), "gz/index.html");
+    }
+
+    /// Create an empty bare repository that is unique to the test.
+    fn temp_repo(name: &str) -> Repository {
+        let path = temp_path(name);
+        let _ = fs::remove_dir_all(&path);
+        Repository::init_bare(&path).unwrap()
+    }
+
+    /// Return the paths and blobs of all files in the tree.
+    fn tree_files(repo: &Repository, root: Oid) -> BTreeMap<String, Oid> {
+        let mut files = BTreeMap::new();
+        let tree = repo.find_tree(root).unwrap();
+        tree.walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+            if entry.kind() == Some(ObjectType::Blob) {
+                files.insert(format!("{}{}", dir, entry.name().unwrap()), entry.id());
+            }
+            git2::TreeWalkResult::Ok
+        })
+        .unwrap();
+        files
+    }
+
+    /// Return a page at `path` whose versions are blobs in the repository.
+    fn test_page(repo: &Repository, path: &str) -> Page {
+        let blob = |suffix: &str| repo.blob(format!("{}{}", path, suffix).as_bytes()).unwrap();
+        let source = blob("");
+        Page {
+            path: path.to_string(),
+            kind: path.to_string(),
+            source,
+            blobs: MinifiedBlobs {
+                minified: source,
+                gz: blob(".gz"),
+                br: blob(".br"),
+                deflate: None,
+                ..entry(0)
+            },
+            from_cache: false,
+        }
+    }
+
+    #[test]
+    fn place_compressed_follows_the_layout() {
+        let repo = temp_repo("place-layout");
+        let pages = [test_page(&repo, "index.html"), test_page(&repo, "docs/about")];
+        let opts = Options {
+            layout_gz: Some("{dir}{stem}.gz{ext}".to_string()),
+            layout_br: Some("br/{path}".to_string()),
+            ..Options::default()
+        };
+        let root = place_compressed(&opts, &repo, None, &pages).unwrap();
+        let files = tree_files(&repo, root);
+        let paths: Vec<&str> = files.keys().map(|p| p.as_str()).collect();
+        assert_eq!(paths, ["br/docs/about", "br/index.html", "docs/about.gz", "index.gz.html"]);
+        assert_eq!(files["index.gz.html"], pages[0].blobs.gz);
+        assert_eq!(files["br/docs/about"], pages[1].blobs.br);
+    }
+
+    #[test]
+    fn place_compressed_rejects_two_files_at_one_path() {
+        let repo = temp_repo("place-collision");
+        // Both pages have the same stem, so without the extension they collide.
+        let pages = [test_page(&repo, "a.html"), test_page(&repo, "a.htm")];
+        let opts = Options { layout_gz: Some("{dir}{stem}.gz".to_string()), ..Options::default() };
+        let err = place_compressed(&opts, &repo, None, &pages).unwrap_err();
+        assert!(err.message().contains("two files at a.gz"), "{}", err.message());
+
+        // A compressed version also can't replace a file that is in the tree.
+        let existing = insert_paths(&repo, None, &[("a.html.br".to_string(), pages[0].source)]);
+        let tree = repo.find_tree(existing.unwrap()).unwrap();
+        let err = place_compressed(&Options::default(), &repo, Some(&tree), &pages).unwrap_err();
+        assert!(err.message().contains("two files at a.html.br"), "{}", err.message());
+    }
+
+    #[test]
+    fn add_page_aliases_copies_pages_to_the_other_convention() {
+        let repo = temp_repo("aliases");
+        let paths = [
+            "index.html",
+            "foo.html",
+            "foo.html.gz",
+            "bar.html",
+            "bar/index.html",
+            "docs/index.html",
+            "docs/index.html.br",
+            "api/index.html",
+            "api.html",
+            "style.css",
+        ];
+        let files: Vec<(String, Oid)> =
+            paths.iter().map(|p| (p.to_string(), repo.blob(p.as_bytes()).unwrap())).collect();
+        let root = insert_paths(&repo, None, &files).unwrap();
+        let blob = |path: &str| files.iter().find(|(p, _)| p == path).unwrap().1;
+
+        let dir = tree_files(&repo, add_page_aliases(&repo, root, PageAlias::Dir).unwrap());
+        let added: Vec<&str> =
+            dir.keys().map(|p| p.as_str()).filter(|p| !paths.contains(p)).collect();
+        assert_eq!(added, ["foo/index.html", "foo/index.html.gz"]);
+        assert_eq!(dir["foo/index.html.gz"], blob("foo.html.gz"));
+        // Existing files are left alone.
+        assert_eq!(dir["bar/index.html"], blob("bar/index.html"));
+
+        let flat = tree_files(&repo, add_page_aliases(&repo, root, PageAlias::Flat).unwrap());
+        let added: Vec<&str> =
+            flat.keys().map(|p| p.as_str()).filter(|p| !paths.contains(p)).collect();
+        assert_eq!(added, ["docs.html", "docs.html.br"]);
+        assert_eq!(flat["docs.html"], blob("docs/index.html"));
+        assert_eq!(flat["api.html"], blob("api.html"));
+    }
+
+    #[test]
+    fn log_page_writes_the_fields_of_the_schema() {
+        let repo = temp_repo("log-page");
+        let page = test_page(&repo, "docs/\"quoted\".html");
+        let fname = temp_path("log.jsonl");
+        let mut out = fs::File::create(&fname).unwrap();
+        log_page(&mut out, "gh-pages", &page, std::time::Duration::from_micros(1500)).unwrap();
+        let line = fs::read_to_string(&fname).unwrap();
+
+        assert!(line.ends_with("}\n") && line.matches('\n').count() == 1, "{}", line);
+        let version = report::LOG_SCHEMA_VERSION;
+        assert!(line.starts_with(&format!("{{\"schema_version\":{},", version)), "{}", line);
+        let schema_version = format!("\"schema_version\": {{ \"const\": {} }}", version);
+        assert!(report::LOG_SCHEMA.contains(&schema_version));
+        assert!(line.contains(r#","path":"docs/\"quoted\".html","#), "{}", line);
+        assert!(line.contains(",\"deflate\":null,"), "{}", line);
+        assert!(line.contains(",\"duration_ms\":1.500}"), "{}", line);
+
+        // The line has exactly the fields that the schema requires, in order.
+        let (_, required) = report::LOG_SCHEMA.split_once("\"required\": [").unwrap();
+        let (required, _) = required.split_once(']').unwrap();
+        let required: Vec<&str> = required.split(',').map(|f| f.trim().trim_matches('"')).collect();
+        let fields: Vec<&str> = line
+            .split(&['{', ','][..])
+            .filter_map(|part| part.strip_prefix('"')?.split_once("\":"))
+            .map(|(field, _)| field)
+            .collect();
+        assert_eq!(fields, required);
+    }
+
+    #[test]
+    fn resolve_source_falls_back_to_head_only_without_a_branch() {
+        let repo = temp_repo("resolve-source");
+        let tree = insert_paths(&repo, None, &[("index.html".to_string(), repo.blob(b"<p>Hi</p>").unwrap())]);
+        let tree = repo.find_tree(tree.unwrap()).unwrap();
+        let sig = git2::Signature::now("Test", "test@example.com").unwrap();
+        let head = repo.commit(Some("HEAD"), &sig, &sig, "Site", &tree, &[]).unwrap();
+        let docs = repo.commit(Some("refs/heads/docs"), &sig, &sig, "Docs", &tree, &[]).unwrap();
+
+        let opts = Options::default();
+        let (description, commit) = resolve_source(&opts, &repo, "gh-pages").unwrap();
+        assert_eq!(description, "No branch gh-pages, using HEAD");
+        assert_eq!(commit.id(), head);
+
+        // Asking for the default branch explicitly means it must exist.
+        let opts = Options { branches_given: true, ..Options::default() };
+        let err = resolve_source(&opts, &repo, "gh-pages").unwrap_err();
+        assert_eq!(err.code(), git2::ErrorCode::NotFound);
+
+        let opts = Options { branches: vec!["docs".to_string()], branches_given: true, ..opts };
+        let (description, commit) = resolve_source(&opts, &repo, "docs").unwrap();
+        assert_eq!(description, "Branch docs");
+        assert_eq!(commit.id(), docs);
+        let err = resolve_source(&opts, &repo, "missing").unwrap_err();
+        assert_eq!(err.code(), git2::ErrorCode::NotFound);
+    }
+}
@@ -0,0 +1,210 @@
+// Minimizer -- Site minifier for MkDocs sites that use the Kilsbergen theme
+// Copyright 2022 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Formatting of the size statistics, for the terminal and for badges.
+
+use std::fmt;
+use std::fs;
+
+use owo_colors::{OwoColorize, Stream};
+
+use minimizer::Sizes;
+
+/// Format a percentage, colored green if it is at most `good`, red if it is
+/// more than `poor`, and yellow in between.
+///
+/// Colors are only used when stdout is a terminal, and `NO_COLOR` is not set.
+fn colored_pct(pct: f32, good: f32, poor: f32) -> String {
+    let text = format!("{:.1}%", pct);
+    if pct <= good {
+        text.if_supports_color(Stream::Stdout, |t| t.green()).to_string()
+    } else if pct <= poor {
+        text.if_supports_color(Stream::Stdout, |t| t.yellow()).to_string()
+    } else {
+        text.if_supports_color(Stream::Stdout, |t| t.red()).to_string()
+    }
+}
+
+/// Formats like the `Display` impl of [`Sizes`], but with colored percentages.
+pub struct Colored<'a>(pub &'a Sizes);
+
+impl<'a> fmt::Display for Colored<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sizes = self.0;
+        write!(
+            f,
+            "Original: {}, Minified: {} ({}), Gzip: {} ({}), Brotli: {} ({})",
+            sizes.original_len,
+            sizes.minified_len,
+            // For minification alone, anything that does not grow is fine.
+            colored_pct(sizes.minified_pct(), 90.0, 100.0),
+            sizes.gz_len,
+            colored_pct(sizes.gz_pct(), 30.0, 50.0),
+            sizes.br_len,
+            colored_pct(sizes.br_pct(), 25.0, 50.0),
+        )?;
+        if sizes.deflate_len > 0 {
+            write!(
+                f,
+                ", Deflate: {} ({})",
+                sizes.deflate_len,
+                colored_pct(sizes.deflate_pct(), 30.0, 50.0),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Return a [shields.io endpoint][endpoint] badge that shows the savings.
+///
+/// The savings are those of Brotli compression relative to the original site,
+/// with the same thresholds for the color as in the terminal output.
+///
+/// [endpoint]: https://shields.io/badges/endpoint-badge
+pub fn badge(sizes: &Sizes) -> String {
+    let br_pct = if sizes.original_len == 0 {
+        100.0
+    } else {
+        sizes.br_pct()
+    };
+    let color = if br_pct <= 25.0 {
+        "green"
+    } else if br_pct <= 50.0 {
+        "yellow"
+    } else {
+        "red"
+    };
+    format!(
+        "{{\"schemaVersion\":1,\"label\":\"minified\",\"message\":\"{:.0}%\",\"color\":\"{}\"}}\n",
+        100.0 - br_pct,
+        color,
+    )
+}
+
+/// Version of the format of the `--log-jsonl` lines, in their `schema_version`.
+///
+/// We bump this when a field changes meaning or goes away. New fields can come
+/// without a bump, consumers should ignore fields that they don't know.
+pub const LOG_SCHEMA_VERSION: u32 = 1;
+
+/// JSON Schema of the `--log-jsonl` lines, printed for `--report-schema`.
+pub const LOG_SCHEMA: &str = r##"{
+  "$schema": "https://json-schema.org/draft/2020-12/schema",
+  "title": "Minimizer --log-jsonl line",
+  "description": "A page that Minimizer processed, one per line.",
+  "type": "object",
+  "required": [
+    "schema_version", "branch", "path", "source", "minified", "gz", "br", "deflate",
+    "original_len", "minified_len", "gz_len", "br_len", "deflate_len", "cache_hit",
+    "duration_ms"
+  ],
+  "properties": {
+    "schema_version": { "const": 1 },
+    "branch": { "type": "string", "description": "The branch or revision." },
+    "path": { "type": "string", "description": "Path relative to the root of the site." },
+    "source": { "$ref": "#/$defs/oid", "description": "The source blob." },
+    "minified": { "$ref": "#/$defs/oid" },
+    "gz": { "$ref": "#/$defs/oid" },
+    "br": { "$ref": "#/$defs/oid" },
+    "deflate": {
+      "oneOf": [{ "$ref": "#/$defs/oid" }, { "type": "null" }],
+      "description": "Null without --deflate."
+    },
+    "original_len": { "$ref": "#/$defs/len" },
+    "minified_len": { "$ref": "#/$defs/len" },
+    "gz_len": { "$ref": "#/$defs/len" },
+    "br_len": { "$ref": "#/$defs/len" },
+    "deflate_len": { "$ref": "#/$defs/len", "description": "Zero without --deflate." },
+    "cache_hit": { "type": "boolean" },
+    "duration_ms": { "type": "number", "minimum": 0 }
+  },
+  "$defs": {
+    "oid": { "type": "string", "pattern": "^[0-9a-f]{40}$" },
+    "len": { "type": "integer", "minimum": 0, "description": "Size in bytes." }
+  }
+}"##;
+
+/// Format a string as a JSON string literal, with quotes.
+pub fn json_string(s: &str) -> String {
+    use std::fmt::Write;
+
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            ch if (ch as u32) < 0x20 => write!(result, "\\u{:04x}", ch as u32).unwrap(),
+            ch => result.push(ch),
+        }
+    }
+    result.push('"');
+    result
+}
+
+/// Print a histogram of the Brotli-compressed size as percentage of the original.
+///
+/// Pages are binned in steps of 20%, pages that grew go in the last bin.
+pub fn print_histogram<'a, I: IntoIterator<Item = &'a Sizes>>(sizes: I) {
+    let labels = ["0-20%", "20-40%", "40-60%", "60-80%", "80-100%", ">100%"];
+    let mut counts = [0_usize; 6];
+    for page in sizes {
+        let bin = (page.br_pct() / 20.0).max(0.0) as usize;
+        counts[bin.min(counts.len() - 1)] += 1;
+    }
+
+    // Scale the bars so the largest bin fills the width.
+    let width = 50;
+    let max_count = counts.iter().copied().max().unwrap_or(0).max(1);
+    for (label, count) in labels.iter().zip(counts) {
+        let bar = "#".repeat((count * width + max_count - 1) / max_count);
+        let line = format!("{:>7} {:>6} {}", label, count, bar);
+        println!("{}", line.trim_end());
+    }
+}
+
+/// Statistics about a run, to export to Prometheus.
+pub struct Metrics {
+    /// The total sizes of all pages.
+    pub sizes: Sizes,
+
+    /// The number of pages.
+    pub pages: usize,
+
+    /// The number of pages that we did not minify, because they were cached.
+    pub cache_hits: usize,
+
+    /// How long the run took, up to the end of the checkout.
+    pub duration: std::time::Duration,
+}
+
+impl Metrics {
+    /// Write the metrics in the Prometheus text format, for the textfile
+    /// collector of node_exporter.
+    pub fn save(&self, fname: &str) -> std::io::Result<()> {
+        use std::io::Write;
+
+        let sizes = &self.sizes;
+        let gauges = [
+            ("original_bytes", "Total size of the source pages.", sizes.original_len as f64),
+            ("minified_bytes", "Total size of the minified pages.", sizes.minified_len as f64),
+            ("gzip_bytes", "Total size of the Gzip versions of pages.", sizes.gz_len as f64),
+            ("brotli_bytes", "Total size of the Brotli versions of pages.", sizes.br_len as f64),
+            ("pages", "Number of pages that were minified.", self.pages as f64),
+            ("cache_hits", "Number of pages that came from the cache.", self.cache_hits as f64),
+            ("duration_seconds", "Duration of the run.", self.duration.as_secs_f64()),
+        ];
+        let mut out = std::io::BufWriter::new(fs::File::create(fname)?);
+        for (name, help, value) in gauges {
+            writeln!(out, "# HELP minimizer_{} {}", name, help)?;
+            writeln!(out, "# TYPE minimizer_{} gauge", name)?;
+            writeln!(out, "minimizer_{} {}", name, value)?;
+        }
+        out.flush()
+    }
+}
@@ -0,0 +1,147 @@
+// Minimizer -- Site minifier for MkDocs sites that use the Kilsbergen theme
+// Copyright 2022 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Upload of the minimized tree to an S3-compatible bucket, for `--output-s3`.
+//!
+//! We shell out to the `aws` command-line tool, so the credentials, region,
+//! and endpoint come from the usual `AWS_*` environment variables and config.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use git2::{ObjectType, Oid, Repository};
+
+use crate::config::Options;
+use crate::{path_in_target, render_layout, Page};
+
+type Result<T> = std::result::Result<T, git2::Error>;
+
+/// Return the content type of a file, by the extension of its name.
+fn content_type(name: &str) -> &'static str {
+    let ext = name.rsplit_once('.').map_or("", |(_, ext)| ext);
+    match ext {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "xhtml" => "application/xhtml+xml",
+        "xml" => "application/xml",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "tsv" => "text/tab-separated-values; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Upload one object with `aws s3 cp`, with the content on stdin.
+fn put_object(
+    dest: &str,
+    content: &[u8],
+    content_type: &str,
+    encoding: Option<&str>,
+) -> io::Result<()> {
+    use std::io::Write;
+
+    let mut command = Command::new("aws");
+    command.args(["s3", "cp", "-", dest, "--content-type", content_type]);
+    if let Some(encoding) = encoding {
+        command.args(["--content-encoding", encoding]);
+    }
+    let mut child = command.stdin(Stdio::piped()).stdout(Stdio::null()).spawn()?;
+
+    // Stdout goes nowhere, so the child can't block on it while we write.
+    let mut stdin = child.stdin.take().expect("We requested a pipe for stdin.");
+    stdin.write_all(content)?;
+    drop(stdin);
+
+    let status = child.wait()?;
+    if !status.success() {
+        let message = format!("aws exited with {}", status);
+        return Err(io::Error::new(io::ErrorKind::Other, message));
+    }
+    Ok(())
+}
+
+/// Upload every file in the tree to `url`, such as `s3://bucket/prefix`.
+///
+/// The compressed versions of pages and reports get the content type of the
+/// page or report, and a `Content-Encoding` for how they are compressed, so
+/// the bucket serves them with the right headers. We recognize the versions of
+/// pages by oid, so this works for any layout. Pages get the content type of
+/// the name we classified them by, so `--content-type` applies here too.
+pub(crate) fn upload_tree(
+    opts: &Options,
+    repo: &Repository,
+    root: Oid,
+    pages: &[Page],
+    reports: &[(&String, Vec<u8>)],
+    url: &str,
+) -> Result<()> {
+    let mut encodings = BTreeMap::new();
+    let mut plain_types = BTreeMap::new();
+    for page in pages {
+        let content_type = content_type(&page.kind);
+        plain_types.insert(page.blobs.minified, content_type);
+        encodings.insert(page.blobs.gz, ("gzip", content_type));
+        encodings.insert(page.blobs.br, ("br", content_type));
+        if let Some(deflate) = page.blobs.deflate {
+            encodings.insert(deflate, ("deflate", content_type));
+        }
+    }
+
+    // Reports in the tree have their compressed versions where the layout
+    // templates put them, see `write_reports`.
+    let tree = repo.find_tree(root)?;
+    for path in reports.iter().filter_map(|(fname, _)| path_in_target(opts, fname)) {
+        let content_type = content_type(&path);
+        let layouts = [(&opts.layout_gz, "gzip"), (&opts.layout_br, "br")];
+        for (template, encoding) in layouts {
+            let variant = match template {
+                Some(template) => render_layout(template, &path),
+                None => continue,
+            };
+            if let Ok(entry) = tree.get_path(Path::new(&variant)) {
+                encodings.insert(entry.id(), (encoding, content_type));
+            }
+        }
+    }
+
+    let mut files = Vec::new();
+    tree.walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+        if entry.kind() == Some(ObjectType::Blob) {
+            let path = format!("{}{}", dir, entry.name().expect("Invalid name in tree entry."));
+            files.push((path, entry.id()));
+        }
+        git2::TreeWalkResult::Ok
+    })?;
+
+    let url = url.trim_end_matches('/');
+    for (path, oid) in &files {
+        let blob = repo.find_blob(*oid)?;
+        let (encoding, content_type) = match (encodings.get(oid), plain_types.get(oid)) {
+            (Some((encoding, content_type)), _) => (Some(*encoding), *content_type),
+            (None, Some(content_type)) => (None, *content_type),
+            (None, None) => (None, content_type(path)),
+        };
+        let dest = format!("{}/{}", url, path);
+        put_object(&dest, blob.content(), content_type, encoding).map_err(|err| {
+            git2::Error::from_str(&format!("Failed to upload {}: {}", path, err))
+        })?;
+        println!("Uploaded {}.", dest);
+    }
+    println!("Uploaded {} files to {}.", files.len(), url);
+
+    Ok(())
+}
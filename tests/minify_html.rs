@@ -0,0 +1,217 @@
+// Minimizer -- Site minifier for MkDocs sites that use the Kilsbergen theme
+// Copyright 2022 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+use minimizer::{
+    compress_brotli, compress_gzip_fast, decompress_brotli, decompress_gzip, inline_js_len,
+    minify_html, minify_html_js, minify_html_readable, minify_xhtml, minify_xml,
+    normalize_newlines, sniff, strip_meta, BrotliMode, SanityCheck, Sniffed, TrailingNewline,
+    DEFAULT_BANNER,
+};
+
+/// Minify the input and return the result as a string.
+fn minify_str(input: &str) -> String {
+    String::from_utf8(minify_html(input.as_bytes(), DEFAULT_BANNER)).expect("Output should be UTF-8.")
+}
+
+#[test]
+fn minify_html_preserves_whitespace_in_pre() {
+    // Code snippets in the docs have significant leading whitespace, and also
+    // contain blank lines and trailing spaces that must survive minification.
+    let code = "\n    fn main() {\n        println!(\"Hi\");  \n\n    }\n  ";
+    let input = format!(
+        "<!DOCTYPE html>\n<html><head><title>Test</title></head>\n<body>\n  \
+        <p>Some   text.</p>\n  <pre><code>{code}</code></pre>\n</body></html>\n"
+    );
+    let output = minify_str(&input);
+    assert!(
+        output.contains(&format!("<pre><code>{code}</code></pre>")),
+        "Whitespace inside <pre> was altered: {output:?}",
+    );
+    // Outside of <pre>, whitespace should still be collapsed, otherwise this
+    // test would pass trivially.
+    assert!(output.contains("<p>Some text.</p>"), "Output: {output:?}");
+}
+
+#[test]
+fn minify_html_preserves_whitespace_in_textarea() {
+    let content = "  line one\n\n    line two  ";
+    let input = format!(
+        "<!DOCTYPE html>\n<html><head><title>Test</title></head>\n<body>\n  \
+        <textarea>{content}</textarea>\n</body></html>\n"
+    );
+    let output = minify_str(&input);
+    assert!(
+        output.contains(&format!("<textarea>{content}</textarea>")),
+        "Whitespace inside <textarea> was altered: {output:?}",
+    );
+}
+
+#[test]
+fn minify_html_minifies_json_ld_but_not_json() {
+    let json_ld = "{\n  \"@type\": \"WebPage\",\n  \"name\": \"Two  spaces\"\n}";
+    let json = "{\n  \"keep\": \"as is\"\n}";
+    let input = format!(
+        "<!DOCTYPE html>\n<html><head><title>Test</title>\n\
+        <script type=\"application/ld+json\">{json_ld}</script>\n\
+        <script type=\"application/json\">{json}</script>\n\
+        </head>\n<body></body></html>\n"
+    );
+    let output = minify_str(&input);
+    assert!(
+        output.contains(r#">{"@type":"WebPage","name":"Two  spaces"}</script>"#),
+        "JSON-LD was not minified: {output:?}",
+    );
+    assert!(output.contains(json), "JSON data block was altered: {output:?}");
+}
+
+#[test]
+fn minify_xhtml_collapses_whitespace_only() {
+    let input = "<?xml version=\"1.0\"?>\n\
+        <html xmlns=\"http://www.w3.org/1999/xhtml\">\n  <head><title>Test</title></head>\n\
+        <body>\n    <p>Some   <em>text</em>.<br/></p>\n    <pre>  keep\n   this</pre>\n\
+        <!-- a   comment -->\n  </body>\n</html>\n";
+    let output = String::from_utf8(minify_xhtml(input.as_bytes(), "")).unwrap();
+    assert_eq!(
+        output,
+        "<?xml version=\"1.0\"?>\n<html xmlns=\"http://www.w3.org/1999/xhtml\"> \
+        <head><title>Test</title></head> <body> <p>Some <em>text</em>.<br/></p> \
+        <pre>  keep\n   this</pre>\n<!-- a   comment -->\n  </body> </html>\n",
+    );
+}
+
+#[test]
+fn strip_meta_removes_only_named_meta_elements() {
+    let input = "<html><head><meta charset=utf-8><meta name=Generator content=\"mkdocs-1.4\">\
+        <meta name=viewport content=width=device-width></head>\
+        <body><pre>&lt;meta name=generator></pre></body></html>";
+    let names = ["generator".to_string()];
+    let output = strip_meta(input, &names).expect("Should strip the generator.");
+    assert_eq!(
+        output,
+        "<html><head><meta charset=utf-8>\
+        <meta name=viewport content=width=device-width></head>\
+        <body><pre>&lt;meta name=generator></pre></body></html>",
+    );
+}
+
+#[test]
+fn minify_xml_removes_whitespace_between_elements() {
+    let input = "<?xml version=\"1.0\"?>\n<urlset a=\"x > y\">\n  <url>\n    \
+        <loc> https://example.com/ </loc>\n  </url>\n  <!-- a  comment -->\n</urlset>\n";
+    let output = minify_xml(input.as_bytes()).expect("Input is valid.");
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "<?xml version=\"1.0\"?><urlset a=\"x > y\"><url>\
+        <loc> https://example.com/ </loc></url><!-- a  comment --></urlset>",
+    );
+    assert!(minify_xml(b"<urlset><url").is_none());
+}
+
+#[test]
+fn sanity_checks_accept_minified_html_but_not_changed_structure() {
+    let input = "<!DOCTYPE html>\n<html><head><title>Test</title></head>\n<body>\n  \
+        <h2 id=\"usage\">Usage</h2>\n  <p>Some   <em>text</em>.</p>\n</body></html>\n";
+    let output = minify_str(input);
+    for check in [SanityCheck::Tags, SanityCheck::Ids] {
+        assert_eq!(check.check(input, &output), Ok(()), "Output: {output:?}");
+    }
+
+    let lost_tag = output.replace("<em>", "");
+    assert!(SanityCheck::Tags.check(input, &lost_tag).is_err());
+    let lost_id = output.replace("id=usage", "");
+    assert!(SanityCheck::Ids.check(input, &lost_id).is_err());
+}
+
+#[test]
+fn normalize_newlines_converts_to_lf_and_fixes_the_end() {
+    let input = b"<pre>a\r\nb\rc\n</pre>\r\n\n";
+    let normalize = |trailing| String::from_utf8(normalize_newlines(input, trailing)).unwrap();
+    assert_eq!(normalize(TrailingNewline::Keep), "<pre>a\nb\nc\n</pre>\n\n");
+    assert_eq!(normalize(TrailingNewline::Ensure), "<pre>a\nb\nc\n</pre>\n");
+    assert_eq!(normalize(TrailingNewline::Strip), "<pre>a\nb\nc\n</pre>");
+    assert_eq!(normalize_newlines(b"", TrailingNewline::Ensure), b"\n");
+}
+
+#[test]
+fn minify_html_writes_attributes_in_a_fixed_order() {
+    // Minify-html sorts the attributes, quoted ones first. The output depends
+    // on this, so a different order after an upgrade should not go unnoticed.
+    let input = "<!DOCTYPE html>\n<html><head><title>Test</title></head>\n<body>\n  \
+        <a title=\"a b\" href=\"/x\" class=\"c d\">x</a>\n</body></html>\n";
+    let output = minify_str(input);
+    assert!(
+        output.contains(r#"<a class="c d" title="a b" href=/x>x</a>"#),
+        "Output: {output:?}",
+    );
+}
+
+#[test]
+fn minify_html_js_minifies_inline_scripts_only_when_asked() {
+    let script = "\n  var answer = 42;\n  console.log( answer );\n";
+    let input = format!(
+        "<!DOCTYPE html>\n<html><head><title>Test</title></head>\n<body>\n  \
+        <script>{script}</script>\n<script type=\"application/json\">[1, 2]</script>\n\
+        </body></html>\n"
+    );
+    let without = String::from_utf8(minify_html(input.as_bytes(), "")).unwrap();
+    let with = String::from_utf8(minify_html_js(input.as_bytes(), "")).unwrap();
+    // Without minifying js, only the whitespace around the script goes, and
+    // the json does not count either way.
+    assert_eq!(inline_js_len(&without), script.trim().len(), "Output: {without:?}");
+    assert!(inline_js_len(&with) < script.trim().len(), "Output: {with:?}");
+}
+
+#[test]
+fn sniff_tells_html_and_images_apart_from_other_files() {
+    assert_eq!(sniff(b"\xef\xbb\xbf\n<!DOCTYPE html>\n<html>"), Some(Sniffed::Html));
+    assert_eq!(sniff(b"<html lang=en><head>"), Some(Sniffed::Html));
+    assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Some(Sniffed::Image));
+    assert_eq!(sniff(b"RIFF\x24\0\0\0WEBPVP8 "), Some(Sniffed::Image));
+    assert_eq!(sniff(b"RIFF\x24\0\0\0WAVEfmt "), Some(Sniffed::Binary));
+    assert_eq!(sniff(b"\0asm\x01\0\0\0"), Some(Sniffed::Binary));
+    // Text that does not start like html goes by its name.
+    assert_eq!(sniff(b"<htmlx>"), None);
+    assert_eq!(sniff(b"<p>A fragment.</p>"), None);
+    assert_eq!(sniff(b""), None);
+}
+
+#[test]
+fn minify_html_inserts_the_banner_into_html_with_attributes() {
+    let input = "<!DOCTYPE html>\n<html lang=\"en\">\n  <head><title>Test</title></head>\n\
+        <body></body></html>\n";
+    let output = String::from_utf8(minify_html(input.as_bytes(), "Banner\n")).unwrap();
+    assert!(output.contains("<html lang=en><!--\nBanner\n--><head>"), "Output: {output:?}");
+}
+
+#[test]
+fn compress_gzip_fast_round_trips() {
+    let input = b"<p>Some text, some text, some text.</p>".repeat(10);
+    let compressed = compress_gzip_fast(&input);
+    assert!(compressed.len() < input.len());
+    assert_eq!(decompress_gzip(&compressed).unwrap(), input);
+}
+
+#[test]
+fn compress_brotli_round_trips_in_every_mode() {
+    let input = b"<p>Some text, some text, some text.</p>".repeat(10);
+    for mode in [BrotliMode::Generic, BrotliMode::Text, BrotliMode::Font] {
+        let compressed = compress_brotli(&input, 9, mode);
+        assert!(compressed.len() < input.len(), "Mode: {mode:?}");
+        assert_eq!(decompress_brotli(&compressed).unwrap(), input, "Mode: {mode:?}");
+    }
+}
+
+#[test]
+fn minify_html_readable_keeps_comments_and_css() {
+    let input = "<!DOCTYPE html>\n<html><head><title>Test</title>\n\
+        <style>p { color: red; }</style></head>\n<body>\n  \
+        <!-- Copyright notice -->\n  <p>Some   text.</p>\n</body></html>\n";
+    let output = String::from_utf8(minify_html_readable(input.as_bytes())).unwrap();
+    assert!(output.contains("<!-- Copyright notice -->"), "Output: {output:?}");
+    assert!(output.contains("p { color: red; }"), "Output: {output:?}");
+    assert!(output.contains("<p>Some text.</p>"), "Output: {output:?}");
+}
@@ -0,0 +1,73 @@
+// Minimizer -- Site minifier for MkDocs sites that use the Kilsbergen theme
+// Copyright 2022 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+use std::path::Path;
+use std::process::Command;
+
+use git2::{Oid, Repository, Signature};
+
+/// Build a tree from `(path, content)` pairs, creating directories as needed.
+fn build_tree(repo: &Repository, files: &[(&str, &str)]) -> Oid {
+    let mut builder = repo.treebuilder(None).unwrap();
+    let mut dirs: Vec<(&str, Vec<(&str, &str)>)> = Vec::new();
+    for (path, content) in files {
+        match path.split_once('/') {
+            Some((dir, rest)) => match dirs.iter_mut().find(|(name, _)| name == &dir) {
+                Some((_, entries)) => entries.push((rest, content)),
+                None => dirs.push((dir, vec![(rest, content)])),
+            },
+            None => {
+                let blob = repo.blob(content.as_bytes()).unwrap();
+                builder.insert(path, blob, 0o100644).unwrap();
+            }
+        }
+    }
+    for (name, entries) in dirs {
+        let subtree = build_tree(repo, &entries);
+        builder.insert(name, subtree, 0o040000).unwrap();
+    }
+    builder.write().unwrap()
+}
+
+#[test]
+fn theme_is_only_skipped_at_the_root() {
+    let dir = std::env::temp_dir().join(format!("minimizer-skip-dirs-{}", std::process::id()));
+    let repo_path = dir.join("repo");
+    std::fs::create_dir_all(&repo_path).unwrap();
+
+    let repo = Repository::init(&repo_path).unwrap();
+    let page = "<!DOCTYPE html>\n<html><head><title>Test</title></head><body></body></html>\n";
+    let tree_id = build_tree(
+        &repo,
+        &[
+            ("index.html", page),
+            ("theme/base.html", page),
+            ("docs/api/theme/index.html", page),
+        ],
+    );
+    let tree = repo.find_tree(tree_id).unwrap();
+    let sig = Signature::now("Test", "test@example.com").unwrap();
+    repo.commit(Some("refs/heads/gh-pages"), &sig, &sig, "Site", &tree, &[]).unwrap();
+
+    // Run in the temporary directory, so the cache goes there, and no config
+    // file from elsewhere applies.
+    let status = Command::new(env!("CARGO_BIN_EXE_minimizer"))
+        .arg(&repo_path)
+        .current_dir(&dir)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let minimized = repo.revparse_single("refs/minimizer/minimized").unwrap();
+    let minimized = minimized.peel_to_tree().unwrap();
+    let exists = |path: &str| minimized.get_path(Path::new(path)).is_ok();
+    assert!(exists("index.html"));
+    assert!(exists("docs/api/theme/index.html"), "Nested theme dir was dropped.");
+    assert!(!exists("theme"), "Root theme dir was kept.");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}